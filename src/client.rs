@@ -1,107 +1,331 @@
-use std::{error::Error, net::SocketAddr, path::PathBuf};
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    io,
+    net::SocketAddr,
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use clap::ValueEnum;
 
-use log::{debug, error, info};
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use log::{debug, info, warn};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncWriteExt, BufWriter},
+    net::TcpStream,
+    time,
+};
 
 use crate::{
-    crypto::{self, Crypto},
-    sockets::SocketHandler,
-    util::{new_file, FileInfo},
+    crypto::{self, CipherSuite, Crypto, RekeyLimit},
+    handshake::{self, Capabilities, Session},
+    observer::{Event, Observer, Observers},
+    progress::Progress,
+    select::Selection,
+    sockets::{Message, SocketHandler},
+    throttle::RateLimiter,
+    util::{
+        confirm_code, download_paths, interact, new_file, resume_file, sanitize_name, unique_path,
+        walk, FileInfo,
+    },
+    Error, Result,
 };
 
+/// What happens when a received file already exists in the output folder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ConflictPolicy {
+    /// Keep the existing file and don't download
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Save under a free name, e.g. 'file (1).txt'
+    Rename,
+    /// Keep the existing file if its hash matches, otherwise replace it
+    SkipIfHashMatches,
+}
+
 #[derive(Clone)]
 pub struct Client {
     addr: SocketAddr,
     key: String,
     output: PathBuf,
+    selection: Selection,
+    on_conflict: ConflictPolicy,
+    prune: bool,
+    rate_limit: Option<NonZeroU64>,
+    timeout: Option<Duration>,
+    ciphers: Vec<CipherSuite>,
+    rekey: RekeyLimit,
+    confirm_code: bool,
+    progress: Progress,
+    observers: Observers,
 }
 
-impl Client {
-    pub fn new(addr: SocketAddr, key: String, output: PathBuf) -> Self {
-        Self { addr, key, output }
+/// Configures a [`Client`], everything but the server address and the access key is optional.
+pub struct ClientBuilder {
+    client: Client,
+}
+
+impl ClientBuilder {
+    pub fn new(addr: SocketAddr, key: impl Into<String>) -> Self {
+        Self {
+            client: Client {
+                addr,
+                key: key.into(),
+                output: PathBuf::from("."),
+                selection: Selection::default(),
+                on_conflict: ConflictPolicy::Overwrite,
+                prune: false,
+                rate_limit: None,
+                timeout: None,
+                ciphers: CipherSuite::all(),
+                rekey: RekeyLimit::default(),
+                confirm_code: false,
+                progress: Progress::hidden(),
+                observers: Observers::default(),
+            },
+        }
     }
 
-    pub async fn connection(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        info!("Trying to connect to the server at {}", self.addr);
+    /// Folder the files are downloaded to, the current directory by default.
+    pub fn output(mut self, output: impl Into<PathBuf>) -> Self {
+        self.client.output = output.into();
+        self
+    }
 
-        let mut socket = TcpStream::connect(self.addr).await?;
+    pub fn selection(mut self, selection: Selection) -> Self {
+        self.client.selection = selection;
+        self
+    }
 
-        debug!("Connected to the TCP socket at {}", self.addr);
+    pub fn on_conflict(mut self, on_conflict: ConflictPolicy) -> Self {
+        self.client.on_conflict = on_conflict;
+        self
+    }
 
-        let mut handler = SocketHandler::new(&mut socket);
-        let crypto = Crypto::new(&mut handler, true).await?;
-        handler.set_crypto(crypto);
+    /// Deletes local files not present on the server after downloading.
+    pub fn prune(mut self, prune: bool) -> Self {
+        self.client.prune = prune;
+        self
+    }
 
-        info!("Encrypted connection to {} established", self.addr);
+    /// Download bandwidth limit in bytes per second, `None` for no limit.
+    pub fn rate_limit(mut self, rate_limit: Option<NonZeroU64>) -> Self {
+        self.client.rate_limit = rate_limit;
+        self
+    }
 
-        if !self.authorize(&mut handler).await? {
-            error!(
-                "Authorization failed due to an invalid access key '{}'",
-                self.key
-            );
-            return Ok(());
+    /// Limits connecting and every wait for the server, `None` waits indefinitely.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.client.timeout = timeout;
+        self
+    }
+
+    /// Cipher suites accepted by the client in order of preference. The order only counts if the
+    /// server prefers the client's ciphers, otherwise the server's order decides.
+    pub fn ciphers(mut self, ciphers: Vec<CipherSuite>) -> Self {
+        self.client.ciphers = ciphers;
+        self
+    }
+
+    /// Traffic after which the client switches to a new key, 1 GiB or 2^20 frames by default.
+    pub fn rekey(mut self, rekey: RekeyLimit) -> Self {
+        self.client.rekey = rekey;
+        self
+    }
+
+    /// Asks on the terminal whether the verification code matches the server's before authorizing.
+    pub fn confirm_code(mut self, confirm_code: bool) -> Self {
+        self.client.confirm_code = confirm_code;
+        self
+    }
+
+    /// Bars drawn while downloading, hidden by default.
+    pub fn progress(mut self, progress: Progress) -> Self {
+        self.client.progress = progress;
+        self
+    }
+
+    /// Adds an observer, can be called multiple times.
+    pub fn observer(mut self, observer: impl Observer + 'static) -> Self {
+        self.client.observers.add(observer);
+        self
+    }
+
+    pub fn build(self) -> Client {
+        self.client
+    }
+}
+
+impl Client {
+    pub fn builder(addr: SocketAddr, key: impl Into<String>) -> ClientBuilder {
+        ClientBuilder::new(addr, key)
+    }
+
+    pub async fn connection(&self) -> Result<()> {
+        let mut socket = self.connect().await?;
+        let result = self.session(&mut socket).await;
+        self.disconnected(&result);
+
+        result
+    }
+
+    async fn session(&self, socket: &mut TcpStream) -> Result<()> {
+        let mut handler = SocketHandler::new(socket);
+
+        handler.set_timeout(self.timeout);
+
+        if let Some(rate) = self.rate_limit {
+            handler.add_limiter(Arc::new(RateLimiter::new(rate)));
         }
 
+        let session = self.establish(&mut handler).await?;
+
         let metadata = self.metadata(&mut handler).await?;
-        self.requests(&mut handler, metadata).await?;
+        let selected = self.selection.apply(metadata.clone()).await?;
+        self.requests(&mut handler, selected, session.resume)
+            .await?;
+
+        if self.prune {
+            self.prune(&metadata).await?;
+        }
 
         debug!("Connection sequence done, shutting down");
 
         Ok(())
     }
 
-    async fn authorize(
-        &self,
-        handler: &mut SocketHandler<'_>,
-    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
-        debug!("Starting authorization");
+    /// Fetches the manifest of the share without downloading anything.
+    pub async fn list(&self) -> Result<Vec<FileInfo>> {
+        let mut socket = self.connect().await?;
+        let result = self.listing(&mut socket).await;
+        self.disconnected(&result);
+
+        result
+    }
+
+    async fn listing(&self, socket: &mut TcpStream) -> Result<Vec<FileInfo>> {
+        let mut handler = SocketHandler::new(socket);
+        handler.set_timeout(self.timeout);
+
+        self.establish(&mut handler).await?;
+
+        let metadata = self.metadata(&mut handler).await?;
+        handler.send_message(&Message::Bye).await?;
+
+        debug!("Listing done, shutting down");
+
+        Ok(metadata)
+    }
+
+    async fn connect(&self) -> Result<TcpStream> {
+        info!("Trying to connect to the server at {}", self.addr);
+
+        let socket = match self.timeout {
+            Some(limit) => match time::timeout(limit, TcpStream::connect(self.addr)).await {
+                Ok(socket) => socket.map_err(Error::Network)?,
+                Err(_) => return Err(Error::Network(io::ErrorKind::TimedOut.into())),
+            },
+            None => TcpStream::connect(self.addr)
+                .await
+                .map_err(Error::Network)?,
+        };
+
+        debug!("Connected to the TCP socket at {}", self.addr);
+        self.observers.emit(Event::Connected { peer: self.addr });
+
+        Ok(socket)
+    }
+
+    fn disconnected<T>(&self, result: &Result<T>) {
+        self.observers.emit(Event::Disconnected {
+            peer: self.addr,
+            error: result.as_ref().err(),
+        });
+    }
 
-        let msg = self.key.as_bytes().to_vec();
-        handler.send(&msg).await?;
+    /// Runs the hello exchange, key exchange and authorization, failing with [`Error::Auth`] on an invalid key.
+    async fn establish(&self, handler: &mut SocketHandler<'_>) -> Result<Session> {
+        let capabilities = Capabilities {
+            ciphers: self.ciphers.clone(),
+            ..Default::default()
+        };
 
-        let buf = handler.recv().await?;
-        let msg = String::from_utf8(buf)?;
-        let msg = msg.trim();
+        let session = handshake::hello(handler, capabilities, true).await?;
+        let mut crypto = Crypto::new(handler, &self.key, true, &session).await?;
+        crypto.set_rekey_limit(self.rekey);
 
-        if msg == "DISCONNECT" {
-            return Ok(false);
+        let code = crypto.code();
+        info!("Verification code: {}", code);
+
+        if self.confirm_code {
+            let addr = self.addr;
+            let confirmed = interact(move || confirm_code(&addr, &code)).await?;
+
+            if !confirmed {
+                handler.send_message(&Message::CodeRejected).await?;
+                return Err(Error::Verification);
+            }
         }
 
-        debug!("Authorization successfully done");
+        if !self.authorize(handler, &crypto).await? {
+            return Err(Error::Auth);
+        }
 
-        Ok(true)
+        handler.set_crypto(crypto);
+
+        info!("Encrypted connection to {} established", self.addr);
+        self.observers.emit(Event::Authorized { peer: self.addr });
+
+        Ok(session)
     }
 
-    async fn metadata(
-        &self,
-        handler: &mut SocketHandler<'_>,
-    ) -> Result<Vec<FileInfo>, Box<dyn Error + Send + Sync>> {
-        debug!("Starting to receive metadata");
+    async fn authorize(&self, handler: &mut SocketHandler<'_>, crypto: &Crypto) -> Result<bool> {
+        debug!("Starting authorization");
 
-        let buf = handler.recv().await?;
-        let amt = String::from_utf8(buf.clone())?.parse::<usize>()?;
-        handler.send(&buf).await?; // confirmation
+        let msg = Message::Auth(crypto.confirmation(true));
+        handler.send_message(&msg).await?;
 
-        debug!("Confirmed metadata amount ({})", amt);
+        let reply = match handler.recv_message().await? {
+            Message::AuthResult(reply) => reply,
+            other => return Err(other.unexpected("AuthResult")),
+        };
 
-        let mut metadata = Vec::new();
+        // a server without the key can't produce a valid tag, whatever result it claims
+        let is_valid = match reply {
+            Some(tag) => crypto.verify_confirmation(false, &tag),
+            None => false,
+        };
 
-        while metadata.len() < amt {
-            let buf = handler.recv().await?;
-            let data = String::from_utf8(buf)?;
+        if is_valid {
+            debug!("Authorization successfully done");
+        }
 
-            let split = data.split(':').collect::<Vec<&str>>();
-            let name = split[0].trim().to_string();
-            let size = split[1].trim().parse::<u64>()?;
-            let hash = split[2].trim().to_string();
+        Ok(is_valid)
+    }
 
-            debug!("Metadata of file '{}' received successfully", name);
+    async fn metadata(&self, handler: &mut SocketHandler<'_>) -> Result<Vec<FileInfo>> {
+        debug!("Starting to receive metadata");
 
-            let info = FileInfo::new(name, size, hash);
+        let metadata = match handler.recv_message().await? {
+            Message::Manifest(metadata) => metadata,
+            other => return Err(other.unexpected("Manifest")),
+        };
 
-            metadata.push(info);
+        // a single hostile entry rejects the whole manifest before anything is written
+        for file in &metadata {
+            sanitize_name(&file.name)?;
         }
 
+        debug!("Metadata of {} files received successfully", metadata.len());
+        self.observers.emit(Event::Manifest {
+            peer: self.addr,
+            files: &metadata,
+        });
+
         Ok(metadata)
     }
 
@@ -109,40 +333,213 @@ impl Client {
         &self,
         handler: &mut SocketHandler<'_>,
         metadata: Vec<FileInfo>,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        resume: bool,
+    ) -> Result<()> {
         info!("Starting to send requests");
 
+        let mut progress = self.progress.clone();
+        progress.start(&metadata);
+
         for file in metadata {
-            let (mut handle, path) = new_file(self.output.clone(), &file.name).await?;
-            let msg = file.hash.as_bytes().to_vec();
-            handler.send(&msg).await?;
+            let (target, partial) = download_paths(self.output.clone(), &file.name)?;
 
+            if target.is_file() && self.keep_existing(&target, &file) {
+                info!("Skipping existing file '{}'", file.display_name());
+                progress.skip(&file);
+                continue;
+            }
+
+            if let Err(e) = self
+                .download(handler, &file, &partial, target, resume, &progress)
+                .await
+            {
+                self.observers.emit(Event::Failed {
+                    peer: self.addr,
+                    file: &file,
+                    error: &e,
+                });
+                return Err(e);
+            }
+
+            self.observers.emit(Event::Verified {
+                peer: self.addr,
+                file: &file,
+            });
+
+            info!("File '{}' successfully transferred", file.hash);
+        }
+
+        handler.send_message(&Message::Bye).await?;
+        progress.finish();
+
+        info!("All requests successfully done");
+
+        Ok(())
+    }
+
+    /// Requests a single file and moves it to `target` once its hash is verified. A resumed file
+    /// that fails verification is downloaded once more from the start, its partial data being stale.
+    async fn download(
+        &self,
+        handler: &mut SocketHandler<'_>,
+        file: &FileInfo,
+        partial: &Path,
+        mut target: PathBuf,
+        resume: bool,
+        progress: &Progress,
+    ) -> Result<()> {
+        let resumed = match resume {
+            true => resume_file(partial, file.size).await?,
+            false => None,
+        };
+
+        let (handle, offset) = match resumed {
+            Some(resumed) => resumed,
+            None => (new_file(partial).await?, 0),
+        };
+
+        let mut check_hash = self
+            .receive(handler, file, partial, handle, offset, progress)
+            .await?;
+
+        if check_hash != file.hash && offset > 0 {
+            warn!(
+                "Resumed file '{}' doesn't match, downloading it from the start",
+                file.hash
+            );
+            progress.rewind(file.size);
+
+            let handle = new_file(partial).await?;
+            check_hash = self
+                .receive(handler, file, partial, handle, 0, progress)
+                .await?;
+        }
+
+        if check_hash != file.hash {
+            fs::remove_file(partial).await?;
+            return Err(Error::Integrity(format!(
+                "Hash of file '{}' doesn't match",
+                file.hash
+            )));
+        }
+
+        if self.on_conflict == ConflictPolicy::Rename && target.exists() {
+            target = unique_path(&target);
+        }
+
+        fs::rename(partial, &target).await?;
+
+        Ok(())
+    }
+
+    /// Requests a file from `offset` on, appends it to `partial` and acknowledges the resulting hash.
+    async fn receive(
+        &self,
+        handler: &mut SocketHandler<'_>,
+        file: &FileInfo,
+        partial: &Path,
+        mut handle: BufWriter<File>,
+        offset: u64,
+        progress: &Progress,
+    ) -> Result<String> {
+        let msg = Message::Request {
+            hash: file.hash.clone(),
+            offset,
+        };
+        handler.send_message(&msg).await?;
+
+        if offset > 0 {
+            info!("Resuming file '{}' from byte {}", file.hash, offset);
+        } else {
             info!("Requesting file '{}'", file.hash);
+        }
 
-            let mut remaining = file.size;
+        let bar = progress.file(file, offset);
+        let mut remaining = file.size - offset;
 
-            while remaining != 0 {
-                let buf = handler.recv().await?;
-                handle.write_all(&buf).await?;
-                handle.flush().await?;
-                remaining -= buf.len() as u64;
+        while remaining != 0 {
+            let buf = match handler.recv_message().await? {
+                Message::Chunk(buf) => buf,
+                other => return Err(other.unexpected("Chunk")),
+            };
 
-                debug!("File '{}': {} bytes remaining", file.hash, remaining);
+            if buf.len() as u64 > remaining {
+                return Err(Error::Integrity(format!(
+                    "File '{}' exceeds the announced size",
+                    file.hash
+                )));
             }
 
-            let check_hash = crypto::try_hash(&path).unwrap();
-            let msg = check_hash.as_bytes().to_vec();
-            handler.send(&msg).await?;
+            handle.write_all(&buf).await?;
+            handle.flush().await?;
+            remaining -= buf.len() as u64;
+            progress.advance(&bar, buf.len() as u64);
+            self.observers.emit(Event::Chunk {
+                peer: self.addr,
+                file,
+                position: file.size - remaining,
+                size: buf.len(),
+            });
+
+            debug!("File '{}': {} bytes remaining", file.hash, remaining);
+        }
 
-            if check_hash != file.hash {
-                return Err("Unsuccessful file transfer, hashes don't match".into());
-            }
+        progress.finish_file(&bar);
 
-            info!("File '{}' successfully transferred", file.hash);
+        drop(handle);
+
+        let check_hash = crypto::try_hash(partial)?;
+        handler
+            .send_message(&Message::Ack(check_hash.clone()))
+            .await?;
+
+        Ok(check_hash)
+    }
+
+    /// Removes local files (and directories left empty) that aren't part of the remote share.
+    async fn prune(&self, metadata: &[FileInfo]) -> Result<()> {
+        info!("Removing local files not present on the server");
+
+        let mut remote = HashSet::new();
+
+        for file in metadata {
+            remote.insert(self.output.join(sanitize_name(&file.name)?));
         }
 
-        info!("All requests successfully done");
+        let mut local = Vec::new();
+        walk(&self.output, &mut local)?;
+
+        for path in local.iter().filter(|p| !remote.contains(*p)) {
+            info!("Removing '{}'", path.display());
+            fs::remove_file(path).await?;
+        }
+
+        // deepest directories first, removal only succeeds when empty
+        let mut dirs = local
+            .iter()
+            .flat_map(|p| p.ancestors().skip(1))
+            .filter(|d| d.starts_with(&self.output) && *d != self.output)
+            .collect::<Vec<_>>();
+        dirs.sort_by_key(|d| (Reverse(d.components().count()), *d));
+        dirs.dedup();
+
+        for dir in dirs {
+            if fs::remove_dir(dir).await.is_ok() {
+                debug!("Removed empty directory '{}'", dir.display());
+            }
+        }
 
         Ok(())
     }
+
+    fn keep_existing(&self, target: &Path, file: &FileInfo) -> bool {
+        match self.on_conflict {
+            ConflictPolicy::Skip => true,
+            ConflictPolicy::Overwrite | ConflictPolicy::Rename => false,
+            ConflictPolicy::SkipIfHashMatches => match crypto::try_hash(target) {
+                Ok(hash) => hash == file.hash,
+                Err(_) => false,
+            },
+        }
+    }
 }
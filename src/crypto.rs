@@ -1,111 +1,585 @@
-use std::{error::Error, path::Path};
+use std::path::Path;
 
 use aes_gcm::{
     aead::{consts::U12, Aead},
-    aes::Aes256,
-    Aes256Gcm, AesGcm, KeyInit, Nonce,
+    Aes256Gcm, KeyInit, Nonce,
 };
+use chacha20poly1305::ChaCha20Poly1305;
+use clap::ValueEnum;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
 use log::debug;
-use rand::{rngs::OsRng, RngCore};
-use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
+use sha2::{Digest, Sha256};
+use spake2::{Ed25519Group, Identity, Password, Spake2};
 
-use crate::sockets::SocketHandler;
+use crate::{handshake::Session, sockets::SocketHandler, Error, Result};
 
-const AES_NONCE_SIZE: usize = 12;
-const DH_PBK_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const EPOCH_SIZE: usize = 4;
+const SEQ_SIZE: usize = 8;
+const PAKE_IDENTITY: &[u8] = b"contego";
+const KEY_SIZE: usize = 32;
+const CLIENT_KEY_LABEL: &[u8] = b"contego client to server key";
+const SERVER_KEY_LABEL: &[u8] = b"contego server to client key";
+const CONFIRM_KEY_LABEL: &[u8] = b"contego confirmation key";
+const RESUMPTION_KEY_LABEL: &[u8] = b"contego resumption key";
+const REKEY_LABEL: &[u8] = b"contego rekey";
+const CODE_LABEL: &[u8] = b"contego verification code";
+const CODE_MODULUS: u32 = 1_000_000;
+const CLIENT_CONFIRM_LABEL: &[u8] = b"contego client confirmation";
+const SERVER_CONFIRM_LABEL: &[u8] = b"contego server confirmation";
+
+/// AEAD algorithms available for the session, negotiated during the hello exchange.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CipherSuite {
+    /// AES-256-GCM, fastest with hardware AES support
+    #[value(name = "aes256-gcm")]
+    Aes256Gcm,
+    /// ChaCha20-Poly1305, fastest without hardware AES support
+    #[value(name = "chacha20-poly1305")]
+    ChaCha20Poly1305,
+}
+
+impl CipherSuite {
+    /// Every supported suite in the default preference order.
+    pub fn all() -> Vec<Self> {
+        vec![CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305]
+    }
+
+    pub fn id(self) -> u8 {
+        match self {
+            CipherSuite::Aes256Gcm => 1,
+            CipherSuite::ChaCha20Poly1305 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(CipherSuite::Aes256Gcm),
+            2 => Some(CipherSuite::ChaCha20Poly1305),
+            _ => None,
+        }
+    }
+}
+
+/// A keyed instance of a [`CipherSuite`]. Every suite takes a 256-bit key and a 96-bit nonce.
+#[derive(Clone)]
+enum Cipher {
+    // the expanded AES key schedule is much larger than the ChaCha20 key
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+}
+
+impl Cipher {
+    fn new(suite: CipherSuite, key: &[u8; KEY_SIZE]) -> Self {
+        match suite {
+            CipherSuite::Aes256Gcm => Cipher::Aes256Gcm(Box::new(Aes256Gcm::new(key.into()))),
+            CipherSuite::ChaCha20Poly1305 => {
+                Cipher::ChaCha20Poly1305(ChaCha20Poly1305::new(key.into()))
+            }
+        }
+    }
+
+    fn encrypt(&self, nonce: &Nonce<U12>, data: &[u8]) -> aes_gcm::aead::Result<Vec<u8>> {
+        match self {
+            Cipher::Aes256Gcm(c) => c.encrypt(nonce, data),
+            Cipher::ChaCha20Poly1305(c) => c.encrypt(nonce, data),
+        }
+    }
+
+    fn decrypt(&self, nonce: &Nonce<U12>, data: &[u8]) -> aes_gcm::aead::Result<Vec<u8>> {
+        match self {
+            Cipher::Aes256Gcm(c) => c.decrypt(nonce, data),
+            Cipher::ChaCha20Poly1305(c) => c.decrypt(nonce, data),
+        }
+    }
+}
+
+/// Amount of traffic after which a direction switches to the next key of its ratchet.
+/// Both sides may use different limits, the new epoch is announced in the frame header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RekeyLimit {
+    pub bytes: u64,
+    pub frames: u64,
+}
+
+impl Default for RekeyLimit {
+    fn default() -> Self {
+        Self {
+            bytes: 1 << 30,
+            frames: 1 << 20,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct Crypto {
-    cipher: AesGcm<Aes256, U12>,
-    rng: OsRng,
+    sender: Direction,
+    receiver: Direction,
+    limit: RekeyLimit,
+    keys: Keys,
+    transcript: Vec<u8>,
+}
+
+/// Cipher state of one direction of the session.
+#[derive(Clone)]
+struct Direction {
+    suite: CipherSuite,
+    key: [u8; KEY_SIZE],
+    cipher: Cipher,
+    epoch: u32,
+    seq: u64,
+    frames: u64,
+    bytes: u64,
+}
+
+impl Direction {
+    fn new(suite: CipherSuite, key: [u8; KEY_SIZE]) -> Self {
+        Self {
+            suite,
+            key,
+            cipher: Cipher::new(suite, &key),
+            epoch: 0,
+            seq: 0,
+            frames: 0,
+            bytes: 0,
+        }
+    }
+
+    /// State of the next epoch, its key is derived from the current one but not vice versa.
+    fn ratcheted(&self) -> Result<Self> {
+        let epoch = match self.epoch.checked_add(1) {
+            Some(epoch) => epoch,
+            None => return Err(Error::Crypto(String::from("Key epochs exhausted"))),
+        };
+
+        let mut key = [0u8; KEY_SIZE];
+        Hkdf::<Sha256>::new(None, &self.key)
+            .expand(REKEY_LABEL, &mut key)
+            .expect("key size is a valid HKDF-SHA256 output length");
+
+        Ok(Self {
+            epoch,
+            seq: self.seq,
+            ..Self::new(self.suite, key)
+        })
+    }
+}
+
+/// Key schedule of a session. Every key is expanded with HKDF-SHA256 from the SPAKE2 secret
+/// salted with the transcript hash, binding it to the exact messages both sides exchanged.
+#[derive(Clone)]
+struct Keys {
+    client: [u8; KEY_SIZE],
+    server: [u8; KEY_SIZE],
+    confirmation: [u8; KEY_SIZE],
+    resumption: [u8; KEY_SIZE],
+    code: u32,
+}
+
+impl Keys {
+    fn derive(secret: &[u8], transcript: &[u8]) -> Self {
+        let salt = Sha256::digest(transcript);
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), secret);
+
+        let expand = |label: &[u8]| {
+            let mut key = [0u8; KEY_SIZE];
+            hkdf.expand(label, &mut key)
+                .expect("key size is a valid HKDF-SHA256 output length");
+            key
+        };
+
+        let code = expand(CODE_LABEL);
+        let code = [code[0], code[1], code[2], code[3]];
+
+        Self {
+            client: expand(CLIENT_KEY_LABEL),
+            server: expand(SERVER_KEY_LABEL),
+            confirmation: expand(CONFIRM_KEY_LABEL),
+            resumption: expand(RESUMPTION_KEY_LABEL),
+            code: u32::from_be_bytes(code) % CODE_MODULUS,
+        }
+    }
 }
 
 impl Crypto {
     pub async fn new(
         handler: &mut SocketHandler<'_>,
+        key: &str,
         go_first: bool,
-    ) -> Result<Self, Box<dyn Error + Sync + Send>> {
-        let secret = Self::ecdh(handler, go_first).await?;
-        let cipher = Aes256Gcm::new(secret.as_bytes().into());
-        let rng = OsRng;
+        session: &Session,
+    ) -> Result<Self> {
+        let (secret, pake) = Self::pake(handler, key, go_first).await?;
+
+        // covering the hellos as well authenticates the negotiated version and cipher suite
+        let transcript = [session.transcript.as_slice(), &pake].concat();
+        let keys = Keys::derive(&secret, &transcript);
 
-        Ok(Self { cipher, rng })
+        debug!("Directional session keys derived");
+
+        Ok(Self::with_keys(keys, transcript, go_first, session.cipher))
     }
 
-    async fn ecdh(
+    fn with_keys(keys: Keys, transcript: Vec<u8>, client: bool, suite: CipherSuite) -> Self {
+        // each side encrypts with its own key, so both directions can count from zero
+        let (sender, receiver) = match client {
+            true => (keys.client, keys.server),
+            false => (keys.server, keys.client),
+        };
+
+        Self {
+            sender: Direction::new(suite, sender),
+            receiver: Direction::new(suite, receiver),
+            limit: RekeyLimit::default(),
+            keys,
+            transcript,
+        }
+    }
+
+    /// Traffic limit after which outgoing frames switch to a new key.
+    pub fn set_rekey_limit(&mut self, limit: RekeyLimit) {
+        self.limit = limit;
+    }
+
+    async fn pake(
         handler: &mut SocketHandler<'_>,
+        key: &str,
         go_first: bool,
-    ) -> Result<SharedSecret, Box<dyn Error + Send + Sync>> {
-        debug!("Starting ECDH key exchange");
+    ) -> Result<(Vec<u8>, Vec<u8>)> {
+        debug!("Starting SPAKE2 key exchange");
 
+        // the access key never leaves the host, a peer with a different key
+        // simply ends up with a different session key
+        let (state, msg) = Spake2::<Ed25519Group>::start_symmetric(
+            &Password::new(key.as_bytes()),
+            &Identity::new(PAKE_IDENTITY),
+        );
         let buf: Vec<u8>;
-        let own_sec = EphemeralSecret::new(OsRng);
-        let own_pbk = PublicKey::from(&own_sec);
-        let mut msg = own_pbk.as_bytes().to_vec();
-
-        msg.push(b':'); // manual delimiter
 
         if go_first {
             handler.send_raw(&msg).await?;
-            buf = handler.recv_raw(DH_PBK_SIZE).await?;
+            buf = handler.recv_raw().await?;
         } else {
-            buf = handler.recv_raw(DH_PBK_SIZE).await?;
+            buf = handler.recv_raw().await?;
             handler.send_raw(&msg).await?;
         }
 
-        debug!("Calculating PPK from the shared secret");
+        debug!("Deriving the session key from the shared secret");
+
+        let secret = match state.finish(&buf) {
+            Ok(secret) => secret,
+            Err(e) => return Err(Error::Crypto(format!("Key exchange failed: {:?}", e))),
+        };
+
+        // transcript is always ordered as client message || server message
+        let mut transcript = Vec::new();
+
+        if go_first {
+            transcript.extend_from_slice(&msg);
+            transcript.extend_from_slice(&buf);
+        } else {
+            transcript.extend_from_slice(&buf);
+            transcript.extend_from_slice(&msg);
+        }
+
+        debug!("Session key successfully generated");
+
+        Ok((secret, transcript))
+    }
 
-        let slice: [u8; DH_PBK_SIZE] = buf[..DH_PBK_SIZE].try_into()?;
-        let recv_pbk = PublicKey::from(slice);
-        let pvk = own_sec.diffie_hellman(&recv_pbk);
+    fn confirmation_mac(&self, client: bool) -> Hmac<Sha256> {
+        let label = match client {
+            true => CLIENT_CONFIRM_LABEL,
+            false => SERVER_CONFIRM_LABEL,
+        };
 
-        debug!("PPK successfully generated");
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.keys.confirmation)
+            .expect("HMAC accepts keys of any length");
+        mac.update(label);
+        mac.update(&self.transcript);
 
-        Ok(pvk)
+        mac
     }
 
-    fn nonce(&mut self) -> Nonce<U12> {
-        debug!("Generating new unique nonce (AEAD)");
+    /// Proof of knowing the session key, which in turn proves knowing the access key.
+    pub fn confirmation(&self, client: bool) -> Vec<u8> {
+        self.confirmation_mac(client)
+            .finalize()
+            .into_bytes()
+            .to_vec()
+    }
+
+    pub fn verify_confirmation(&self, client: bool, tag: &[u8]) -> bool {
+        self.confirmation_mac(client).verify_slice(tag).is_ok()
+    }
+
+    /// Short code for comparing with the peer over another channel, e.g. a call. Matching codes
+    /// mean both sides derived the same keys from the same exchange, so nobody sits in between.
+    pub fn code(&self) -> String {
+        let code = format!("{:06}", self.keys.code);
+        format!("{} {}", &code[..3], &code[3..])
+    }
+
+    /// Key reserved for resuming the session later without a full key exchange.
+    pub fn resumption_key(&self) -> &[u8] {
+        &self.keys.resumption
+    }
 
+    /// 96-bit nonce made of 4 zero bytes and the big-endian sequence number.
+    fn nonce(seq: u64) -> Nonce<U12> {
         let mut nonce = Nonce::default();
-        self.rng.fill_bytes(&mut nonce);
+        nonce[NONCE_SIZE - SEQ_SIZE..].copy_from_slice(&seq.to_be_bytes());
 
         nonce
     }
 
-    pub async fn encrypt(&mut self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    /// Encrypts the next frame, prefixed with its key epoch and sequence number.
+    pub async fn encrypt(&mut self, data: &[u8]) -> Result<Vec<u8>> {
         debug!("Encrypting {} bytes payload", data.len());
 
-        let nonce = self.nonce();
-        let encrypted = match self.cipher.encrypt(&nonce, data.as_ref()) {
+        if self.sender.bytes >= self.limit.bytes || self.sender.frames >= self.limit.frames {
+            self.sender = self.sender.ratcheted()?;
+            debug!("Rekeyed outgoing traffic (epoch {})", self.sender.epoch);
+        }
+
+        let seq = self.sender.seq;
+
+        // a nonce must never repeat under the same key
+        self.sender.seq = match seq.checked_add(1) {
+            Some(next) => next,
+            None => return Err(Error::Crypto(String::from("Sequence number exhausted"))),
+        };
+
+        let encrypted = match self.sender.cipher.encrypt(&Self::nonce(seq), data) {
             Ok(data) => data,
-            Err(e) => return Err(format!("Encryption failed: {}", e).into()),
+            Err(e) => return Err(Error::Crypto(format!("Encryption failed: {}", e))),
         };
 
-        let mut data = nonce.to_vec();
+        self.sender.frames += 1;
+        self.sender.bytes += data.len() as u64;
+
+        let mut data = self.sender.epoch.to_be_bytes().to_vec();
+        data.extend_from_slice(&seq.to_be_bytes());
         data.extend_from_slice(&encrypted);
 
         Ok(data)
     }
 
-    pub async fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    /// Decrypts the next frame, rejecting frames that were replayed, dropped or reordered.
+    pub async fn decrypt(&mut self, data: &[u8]) -> Result<Vec<u8>> {
         debug!("Decrypting {} bytes payload", data.len());
 
-        let (nonce_bytes, data) = data.split_at(AES_NONCE_SIZE);
-        let nonce = Nonce::from_slice(nonce_bytes);
-        let decrypted = match self.cipher.decrypt(nonce, data.as_ref()) {
+        if data.len() < EPOCH_SIZE + SEQ_SIZE {
+            return Err(Error::Crypto(String::from(
+                "Decryption failed: payload shorter than the frame header",
+            )));
+        }
+
+        let (epoch, data) = data.split_at(EPOCH_SIZE);
+        let (seq, data) = data.split_at(SEQ_SIZE);
+        let epoch = u32::from_be_bytes(epoch.try_into().expect("split at the epoch size"));
+        let seq = u64::from_be_bytes(seq.try_into().expect("split at the sequence number size"));
+
+        if seq < self.receiver.seq {
+            return Err(Error::Crypto(format!("Replayed frame #{}", seq)));
+        }
+
+        if seq > self.receiver.seq {
+            return Err(Error::Crypto(format!(
+                "Frame #{} received out of order, expected #{}",
+                seq, self.receiver.seq
+            )));
+        }
+
+        // the peer moves on one epoch at a time, the new key is kept only if the frame is genuine
+        let rekeyed = match epoch {
+            e if e == self.receiver.epoch => None,
+            e if Some(e) == self.receiver.epoch.checked_add(1) => Some(self.receiver.ratcheted()?),
+            e => {
+                return Err(Error::Crypto(format!(
+                    "Frame from key epoch {}, expected {}",
+                    e, self.receiver.epoch
+                )))
+            }
+        };
+
+        let receiver = rekeyed.as_ref().unwrap_or(&self.receiver);
+        let decrypted = match receiver.cipher.decrypt(&Self::nonce(seq), data) {
             Ok(data) => data,
-            Err(e) => return Err(format!("Decryption failed: {}", e).into()),
+            Err(e) => return Err(Error::Crypto(format!("Decryption failed: {}", e))),
+        };
+
+        if let Some(receiver) = rekeyed {
+            debug!("Rekeyed incoming traffic (epoch {})", receiver.epoch);
+            self.receiver = receiver;
+        }
+
+        self.receiver.seq = match seq.checked_add(1) {
+            Some(next) => next,
+            None => return Err(Error::Crypto(String::from("Sequence number exhausted"))),
         };
 
         Ok(decrypted)
     }
 }
 
-pub fn try_hash(path: &Path) -> Result<String, Box<dyn Error>> {
+pub fn try_hash(path: &Path) -> Result<String> {
     debug!("Calculating SHA hash");
 
     let hash = sha256::try_digest(path)?;
 
     Ok(hash)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pair(suite: CipherSuite) -> (Crypto, Crypto) {
+        let keys = Keys::derive(b"secret", b"transcript");
+        let client = Crypto::with_keys(keys.clone(), b"transcript".to_vec(), true, suite);
+        let server = Crypto::with_keys(keys, b"transcript".to_vec(), false, suite);
+
+        (client, server)
+    }
+
+    #[tokio::test]
+    async fn cipher_suites() {
+        for suite in CipherSuite::all() {
+            assert_eq!(CipherSuite::from_id(suite.id()), Some(suite));
+
+            let (mut client, mut server) = pair(suite);
+            let data = vec![0xAB; 1024];
+
+            let frame = client.encrypt(&data).await.unwrap();
+            assert_ne!(&frame[EPOCH_SIZE + SEQ_SIZE..][..data.len()], &data[..]);
+            assert_eq!(server.decrypt(&frame).await.unwrap(), data, "{:?}", suite);
+
+            let frame = server.encrypt(&data).await.unwrap();
+            assert_eq!(client.decrypt(&frame).await.unwrap(), data, "{:?}", suite);
+        }
+
+        // same keys under a different suite don't decrypt
+        let (mut client, _) = pair(CipherSuite::Aes256Gcm);
+        let (_, mut server) = pair(CipherSuite::ChaCha20Poly1305);
+        let frame = client.encrypt(b"data").await.unwrap();
+        assert!(server.decrypt(&frame).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn sequenced_frames() {
+        let (mut client, mut server) = pair(CipherSuite::Aes256Gcm);
+
+        for i in 0..3u8 {
+            let frame = client.encrypt(&[i]).await.unwrap();
+            assert_eq!(server.decrypt(&frame).await.unwrap(), vec![i]);
+        }
+
+        // the other direction counts separately
+        let frame = server.encrypt(b"reply").await.unwrap();
+        assert_eq!(client.decrypt(&frame).await.unwrap(), b"reply");
+    }
+
+    #[tokio::test]
+    async fn replayed_and_reordered_frames() {
+        let (mut client, mut server) = pair(CipherSuite::Aes256Gcm);
+
+        let first = client.encrypt(b"first").await.unwrap();
+        let second = client.encrypt(b"second").await.unwrap();
+        let third = client.encrypt(b"third").await.unwrap();
+
+        assert!(server.decrypt(&second).await.is_err());
+        server.decrypt(&first).await.unwrap();
+        assert!(server.decrypt(&first).await.is_err());
+        server.decrypt(&second).await.unwrap();
+
+        // a forged sequence number doesn't match the nonce the frame was sealed with
+        let mut forged = first.clone();
+        forged[EPOCH_SIZE..EPOCH_SIZE + SEQ_SIZE].copy_from_slice(&2u64.to_be_bytes());
+        assert!(server.decrypt(&forged).await.is_err());
+        server.decrypt(&third).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn exhausted_sequence() {
+        let (mut client, _) = pair(CipherSuite::ChaCha20Poly1305);
+        client.sender.seq = u64::MAX;
+
+        assert!(client.encrypt(b"data").await.is_err());
+    }
+
+    #[test]
+    fn key_schedule() {
+        let keys = Keys::derive(b"secret", b"client || server");
+
+        assert_ne!(keys.client, keys.server);
+        assert_ne!(keys.client, keys.confirmation);
+        assert_ne!(keys.server, keys.resumption);
+
+        // both sides derive the same keys, but only from the same transcript
+        let same = Keys::derive(b"secret", b"client || server");
+        let other = Keys::derive(b"secret", b"client || tampered");
+
+        assert_eq!(keys.client, same.client);
+        assert_eq!(keys.server, same.server);
+        assert_ne!(keys.client, other.client);
+        assert_ne!(keys.server, other.server);
+        assert_eq!(keys.code, same.code);
+        assert!(keys.code < CODE_MODULUS);
+    }
+
+    #[test]
+    fn mutual_confirmation() {
+        let (client, server) = pair(CipherSuite::Aes256Gcm);
+
+        assert!(server.verify_confirmation(true, &client.confirmation(true)));
+        assert!(client.verify_confirmation(false, &server.confirmation(false)));
+
+        // a tag can't be reflected back to the side that produced it
+        assert!(!client.verify_confirmation(false, &client.confirmation(true)));
+        assert!(!server.verify_confirmation(true, &server.confirmation(false)));
+    }
+
+    #[test]
+    fn verification_code() {
+        let (client, server) = pair(CipherSuite::Aes256Gcm);
+        let code = client.code();
+
+        assert_eq!(code, server.code());
+        assert_eq!(code.len(), 7);
+        assert!(code[..3]
+            .chars()
+            .chain(code[4..].chars())
+            .all(|c| c.is_ascii_digit()));
+    }
+
+    #[tokio::test]
+    async fn rekeying() {
+        let (mut client, mut server) = pair(CipherSuite::Aes256Gcm);
+        client.set_rekey_limit(RekeyLimit {
+            bytes: u64::MAX,
+            frames: 2,
+        });
+        server.set_rekey_limit(RekeyLimit {
+            bytes: 10,
+            frames: u64::MAX,
+        });
+
+        for i in 0..5u8 {
+            let frame = client.encrypt(&[i]).await.unwrap();
+            assert_eq!(server.decrypt(&frame).await.unwrap(), vec![i]);
+
+            let frame = server.encrypt(&[i; 6]).await.unwrap();
+            assert_eq!(client.decrypt(&frame).await.unwrap(), vec![i; 6]);
+        }
+
+        assert_eq!((client.sender.epoch, server.receiver.epoch), (2, 2));
+        assert_eq!((server.sender.epoch, client.receiver.epoch), (2, 2));
+        assert_ne!(client.sender.key, client.keys.client);
+
+        // skipping an epoch isn't possible without the keys in between
+        let mut frame = client.encrypt(b"data").await.unwrap();
+        frame[..EPOCH_SIZE].copy_from_slice(&4u32.to_be_bytes());
+        assert!(server.decrypt(&frame).await.is_err());
+    }
+}
@@ -0,0 +1,79 @@
+use std::{fmt, io};
+
+use crate::{sockets::MessageError, util::NameError};
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Everything that can go wrong in a client or server, grouped by what the caller can do about it.
+#[derive(Debug)]
+pub enum Error {
+    /// Connection couldn't be established, was lost or timed out, usually worth a retry
+    Network(io::Error),
+    /// Local failure, e.g. an unwritable output folder or a full disk
+    Io(io::Error),
+    /// Malformed or out of order message from the peer
+    Message(MessageError),
+    /// File name in the manifest that would escape or break the output folder
+    Name(NameError),
+    /// Any other violation of the protocol, e.g. oversized frames or no common features
+    Protocol(String),
+    /// Error reported by the peer itself, e.g. a busy server
+    Peer(String),
+    /// The peer uses a different access key
+    Auth,
+    /// The verification code wasn't confirmed, someone may be intercepting the connection
+    Verification,
+    /// Transferred data doesn't match the announced size or hash
+    Integrity(String),
+    /// Key exchange, encryption or decryption failed
+    Crypto(String),
+    /// Invalid local setup, e.g. a bad address, path or selection
+    Config(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Network(e) => write!(f, "Network error: {}", e),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Message(e) => write!(f, "Protocol error: {}", e),
+            Error::Name(e) => write!(f, "Protocol error: {}", e),
+            Error::Protocol(reason) => write!(f, "Protocol error: {}", reason),
+            Error::Peer(reason) => write!(f, "Peer error: {}", reason),
+            Error::Auth => write!(f, "Authorization failed due to an invalid access key"),
+            Error::Verification => write!(f, "Verification code was rejected"),
+            Error::Integrity(reason) => write!(f, "Integrity error: {}", reason),
+            Error::Crypto(reason) => write!(f, "Cryptographic error: {}", reason),
+            Error::Config(reason) => write!(f, "Configuration error: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Network(e) | Error::Io(e) => Some(e),
+            Error::Message(e) => Some(e),
+            Error::Name(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<MessageError> for Error {
+    fn from(e: MessageError) -> Self {
+        Error::Message(e)
+    }
+}
+
+impl From<NameError> for Error {
+    fn from(e: NameError) -> Self {
+        Error::Name(e)
+    }
+}
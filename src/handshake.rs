@@ -0,0 +1,234 @@
+use log::{debug, info};
+
+use crate::{
+    crypto::CipherSuite,
+    sockets::{Message, SocketHandler},
+    Error, Result,
+};
+
+pub const PROTOCOL_VERSION: u16 = 1;
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+}
+
+impl Compression {
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Capabilities {
+    pub ciphers: Vec<CipherSuite>,
+    pub compression: Vec<Compression>,
+    pub resume: bool,
+    /// Set by the server to pick the cipher suite in the client's preference order instead of its own
+    pub prefer_client_ciphers: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            ciphers: CipherSuite::all(),
+            compression: vec![Compression::None],
+            resume: true,
+            prefer_client_ciphers: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u16,
+    pub min_version: u16,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    pub fn new(capabilities: Capabilities) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Session {
+    pub version: u16,
+    pub cipher: CipherSuite,
+    pub compression: Compression,
+    pub resume: bool,
+    /// Encoded client hello || server hello as sent over the wire, authenticated by the key exchange
+    pub transcript: Vec<u8>,
+}
+
+/// Resolves the common feature set of both peers. The server's preference order wins, unless it
+/// defers to the client's cipher order.
+pub fn negotiate(server: &Hello, client: &Hello) -> Result<Session> {
+    let version = server.version.min(client.version);
+
+    if version < server.min_version.max(client.min_version) {
+        return Err(Error::Protocol(format!(
+            "Incompatible protocol versions (server: {}, client: {})",
+            server.version, client.version
+        )));
+    }
+
+    let (server_caps, client_caps) = (&server.capabilities, &client.capabilities);
+
+    let (preferred, other) = match server_caps.prefer_client_ciphers {
+        true => (&client_caps.ciphers, &server_caps.ciphers),
+        false => (&server_caps.ciphers, &client_caps.ciphers),
+    };
+
+    let cipher = match preferred.iter().find(|c| other.contains(c)) {
+        Some(c) => *c,
+        None => {
+            return Err(Error::Protocol(String::from(
+                "No common cipher suite available",
+            )))
+        }
+    };
+
+    let compression = match server_caps
+        .compression
+        .iter()
+        .find(|c| client_caps.compression.contains(c))
+    {
+        Some(c) => *c,
+        None => {
+            return Err(Error::Protocol(String::from(
+                "No common compression method available",
+            )))
+        }
+    };
+
+    Ok(Session {
+        version,
+        cipher,
+        compression,
+        resume: server_caps.resume && client_caps.resume,
+        transcript: Vec::new(),
+    })
+}
+
+pub async fn hello(
+    handler: &mut SocketHandler<'_>,
+    capabilities: Capabilities,
+    go_first: bool,
+) -> Result<Session> {
+    debug!("Starting hello exchange");
+
+    let own = Hello::new(capabilities);
+    let msg = Message::Hello(own.clone()).to_bytes();
+    let reply: Vec<u8>;
+
+    // the raw bytes are kept so that tampering with either hello changes the transcript
+    if go_first {
+        handler.send(&msg).await?;
+        reply = handler.recv().await?;
+    } else {
+        reply = handler.recv().await?;
+        handler.send(&msg).await?;
+    }
+
+    let peer = match Message::from_bytes(&reply)? {
+        Message::Hello(hello) => hello,
+        other => return Err(other.unexpected("Hello")),
+    };
+
+    // both sides resolve the session independently from the same pair of hellos
+    let mut session = if go_first {
+        negotiate(&peer, &own)?
+    } else {
+        negotiate(&own, &peer)?
+    };
+
+    session.transcript = match go_first {
+        true => [msg, reply].concat(),
+        false => [reply, msg].concat(),
+    };
+
+    info!(
+        "Negotiated protocol v{} ({:?}, compression: {:?}, resume: {})",
+        session.version, session.cipher, session.compression, session.resume
+    );
+
+    Ok(session)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn version_fallback() {
+        let server = Hello::new(Capabilities::default());
+        let mut client = Hello::new(Capabilities::default());
+        client.version = PROTOCOL_VERSION + 1;
+
+        let session = negotiate(&server, &client).unwrap();
+
+        assert_eq!(session.version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    #[should_panic]
+    fn incompatible_version() {
+        let server = Hello::new(Capabilities::default());
+        let mut client = Hello::new(Capabilities::default());
+        client.version = PROTOCOL_VERSION + 1;
+        client.min_version = PROTOCOL_VERSION + 1;
+
+        negotiate(&server, &client).unwrap();
+    }
+
+    #[test]
+    fn cipher_preference() {
+        let mut server = Hello::new(Capabilities::default());
+        let mut client = Hello::new(Capabilities::default());
+        client.capabilities.ciphers = vec![CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm];
+
+        // the server's order decides among the suites both support
+        let session = negotiate(&server, &client).unwrap();
+        assert_eq!(session.cipher, CipherSuite::Aes256Gcm);
+
+        server.capabilities.ciphers = vec![CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm];
+        client.capabilities.ciphers = vec![CipherSuite::Aes256Gcm];
+
+        let session = negotiate(&server, &client).unwrap();
+        assert_eq!(session.cipher, CipherSuite::Aes256Gcm);
+
+        // unless the server defers to the client's order
+        server.capabilities.prefer_client_ciphers = true;
+        client.capabilities.ciphers = vec![CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm];
+        server.capabilities.ciphers = vec![CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305];
+
+        let session = negotiate(&server, &client).unwrap();
+        assert_eq!(session.cipher, CipherSuite::ChaCha20Poly1305);
+
+        // a client setting the flag has no say
+        server.capabilities.prefer_client_ciphers = false;
+        client.capabilities.prefer_client_ciphers = true;
+
+        let session = negotiate(&server, &client).unwrap();
+        assert_eq!(session.cipher, CipherSuite::Aes256Gcm);
+
+        client.capabilities.ciphers = Vec::new();
+        assert!(negotiate(&server, &client).is_err());
+    }
+}
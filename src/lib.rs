@@ -1,6 +1,14 @@
 pub mod client;
 pub mod crypto;
+pub mod error;
+pub mod handshake;
+pub mod observer;
 pub mod parser;
+pub mod progress;
+pub mod select;
 pub mod server;
 pub mod sockets;
+pub mod throttle;
 pub mod util;
+
+pub use error::{Error, Result};
@@ -1,17 +1,42 @@
-use std::{error::Error, net::SocketAddr, path::PathBuf};
+use std::{
+    error::Error,
+    net::SocketAddr,
+    num::NonZeroU64,
+    path::PathBuf,
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
-use clap::{command, ArgGroup, Parser, Subcommand};
+use clap::{ArgGroup, Parser, Subcommand};
 
 use contego::{
-    client::Client,
-    parser::{addr_parser, dirpath_parser, filepath_parser},
-    server::Server,
-    util::{ascii, filepaths, metadata, Ip},
+    client::{Client, ConflictPolicy},
+    crypto::CipherSuite,
+    observer::Event,
+    parser::{addr_parser, dirpath_parser, filepath_parser, glob_parser, rate_parser, size_parser},
+    progress::Progress,
+    select::Selection,
+    server::{LimitPolicy, Server},
+    util::{ascii, filepaths, human_size, metadata, FileInfo, Ip},
+    Error as ContegoError,
 };
 use env_logger::Env;
+use glob::Pattern;
+use indicatif_log_bridge::LogWrapper;
 use log::{error, info};
+use serde_json::{json, Value};
 use tokio::{signal, sync::mpsc};
 
+// exit codes of the client commands, documented in the README
+const EXIT_FAILURE: u8 = 1;
+const EXIT_AUTH: u8 = 3;
+const EXIT_NETWORK: u8 = 4;
+const EXIT_INTEGRITY: u8 = 5;
+const EXIT_PARTIAL: u8 = 6;
+
 #[derive(Debug, Parser)]
 #[command(about, version)]
 struct Cli {
@@ -26,10 +51,10 @@ enum Commands {
         /// Access key
         #[clap(short = 'k', long)]
         key: String,
-        /// Path to a source file (alternative to --files)
+        /// Path to a source file listing shareable paths (alternative to --files)
         #[clap(short = 's', long, value_parser = filepath_parser, conflicts_with = "files", group = "input")]
         source: Option<PathBuf>,
-        /// Paths to shareable files (alternative to --source)
+        /// Paths to shareable files or directories (alternative to --source)
         #[clap(short = 'f', long, num_args = 1.., value_parser = filepath_parser, conflicts_with = "source", group = "input")]
         files: Option<Vec<PathBuf>>,
         /// Host port
@@ -44,6 +69,27 @@ enum Commands {
         /// Host locally
         #[clap(short = 'l', long, default_value_t = false)]
         local: bool,
+        /// Maximum amount of concurrently served clients
+        #[clap(short = 'm', long, default_value_t = 8, value_parser = clap::value_parser!(u64).range(1..))]
+        max_clients: u64,
+        /// Policy for connections exceeding --max-clients
+        #[clap(long, value_enum, default_value_t = LimitPolicy::Queue)]
+        on_limit: LimitPolicy,
+        /// Total bandwidth limit in bytes per second (e.g. 500K, 10M)
+        #[clap(short = 'r', long, value_parser = rate_parser)]
+        rate_limit: Option<NonZeroU64>,
+        /// Bandwidth limit per client in bytes per second
+        #[clap(long, value_parser = rate_parser)]
+        client_rate_limit: Option<NonZeroU64>,
+        /// Cipher suites offered to clients in order of preference
+        #[clap(long, value_enum, num_args = 1.., value_delimiter = ',', default_values_t = CipherSuite::all())]
+        ciphers: Vec<CipherSuite>,
+        /// Pick the cipher suite in each client's preference order instead of --ciphers' order
+        #[clap(long, default_value_t = false)]
+        prefer_client_ciphers: bool,
+        /// Ask to confirm each client's verification code before authorizing it
+        #[clap(long, default_value_t = false)]
+        confirm_code: bool,
     },
     Connect {
         /// IP address of the instance
@@ -55,16 +101,69 @@ enum Commands {
         /// Access key
         #[clap(short = 'k', long)]
         key: String,
+        /// Only download files with these exact names
+        #[clap(short = 'n', long = "name", num_args = 1..)]
+        names: Vec<String>,
+        /// Only download files matching these glob patterns
+        #[clap(short = 'i', long, num_args = 1.., value_parser = glob_parser)]
+        include: Vec<Pattern>,
+        /// Skip files matching these glob patterns
+        #[clap(short = 'e', long, num_args = 1.., value_parser = glob_parser)]
+        exclude: Vec<Pattern>,
+        /// Skip files larger than this (e.g. 500K, 2G)
+        #[clap(long, value_parser = size_parser)]
+        max_size: Option<u64>,
+        /// Pick the files to download interactively
+        #[clap(short = 'I', long, default_value_t = false)]
+        interactive: bool,
+        /// Policy for files already existing in the output folder
+        #[clap(long, value_enum, default_value_t = ConflictPolicy::Overwrite, conflicts_with = "sync")]
+        on_conflict: ConflictPolicy,
+        /// Only download files that are missing or changed (same as --on-conflict skip-if-hash-matches)
+        #[clap(long, default_value_t = false)]
+        sync: bool,
+        /// Delete local files not present on the server (requires --sync)
+        #[clap(long, default_value_t = false, requires = "sync")]
+        delete: bool,
+        /// Download bandwidth limit in bytes per second (e.g. 500K, 10M)
+        #[clap(short = 'r', long, value_parser = rate_parser)]
+        rate_limit: Option<NonZeroU64>,
+        /// Cipher suites accepted from the server in order of preference (only decides if the host prefers client ciphers)
+        #[clap(long, value_enum, num_args = 1.., value_delimiter = ',', default_values_t = CipherSuite::all())]
+        ciphers: Vec<CipherSuite>,
+        /// Ask to confirm the server's verification code before authorizing
+        #[clap(long, default_value_t = false)]
+        confirm_code: bool,
+    },
+    List {
+        /// IP address of the instance
+        #[clap(short = 'a', long, value_parser = addr_parser)]
+        addr: SocketAddr,
+        /// Access key
+        #[clap(short = 'k', long)]
+        key: String,
+        /// Print the listing as JSON
+        #[clap(long, default_value_t = false)]
+        json: bool,
     },
 }
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    ascii();
-
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+async fn main() -> Result<ExitCode, Box<dyn Error>> {
     let cli = Cli::parse();
 
+    // keep stdout machine-readable
+    if !matches!(cli.command, Commands::List { json: true, .. }) {
+        ascii();
+    }
+
+    // logging goes through the progress bars so that log lines don't tear them apart
+    let progress = Progress::new();
+    let logger = env_logger::Builder::from_env(Env::default().default_filter_or("info")).build();
+    let level = logger.filter();
+    LogWrapper::new(progress.bars().clone(), logger).try_init()?;
+    log::set_max_level(level);
+
     match cli.command {
         Commands::Host {
             port,
@@ -74,6 +173,13 @@ async fn main() -> Result<(), Box<dyn Error>> {
             chunksize,
             local,
             key,
+            max_clients,
+            on_limit,
+            rate_limit,
+            client_rate_limit,
+            ciphers,
+            prefer_client_ciphers,
+            confirm_code,
         } => {
             let (tx, rx) = mpsc::channel::<()>(1);
 
@@ -85,7 +191,17 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 (false, false) => Ip::V4.fetch(port)?,
             };
 
-            let server = Server::new(display_addr, key, chunksize, metadata, index);
+            let server = Server::builder(display_addr, key)
+                .share(metadata, index)
+                .chunksize(chunksize)
+                .max_clients(max_clients as usize)
+                .limit_policy(on_limit)
+                .rate_limit(rate_limit)
+                .client_rate_limit(client_rate_limit)
+                .ciphers(ciphers)
+                .prefer_client_ciphers(prefer_client_ciphers)
+                .confirm_code(confirm_code)
+                .build();
 
             tokio::spawn(async move {
                 match server.start(rx, &bind_addr).await {
@@ -102,14 +218,105 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 Err(_) => error!("Failed to listen for a Ctrl+C event"),
             };
         }
-        Commands::Connect { addr, out, key } => {
-            let client = Client::new(addr, key, out);
-            match client.connection().await {
-                Ok(_) => {}
-                Err(e) => error!("Error during client execution: {}", e),
+        Commands::Connect {
+            addr,
+            out,
+            key,
+            names,
+            include,
+            exclude,
+            max_size,
+            interactive,
+            on_conflict,
+            sync,
+            delete,
+            rate_limit,
+            ciphers,
+            confirm_code,
+        } => {
+            let selection = Selection {
+                names,
+                include,
+                exclude,
+                max_size,
+                interactive,
+            };
+
+            let on_conflict = match sync {
+                true => ConflictPolicy::SkipIfHashMatches,
+                false => on_conflict,
+            };
+
+            // files already saved when the connection fails make the download partial
+            let verified = Arc::new(AtomicUsize::new(0));
+            let counter = verified.clone();
+
+            let client = Client::builder(addr, key)
+                .output(out)
+                .selection(selection)
+                .on_conflict(on_conflict)
+                .prune(delete)
+                .rate_limit(rate_limit)
+                .ciphers(ciphers)
+                .confirm_code(confirm_code)
+                .progress(progress)
+                .observer(move |event: &Event<'_>| {
+                    if let Event::Verified { .. } = event {
+                        counter.fetch_add(1, Ordering::Relaxed);
+                    }
+                })
+                .build();
+            if let Err(e) = client.connection().await {
+                error!("Error during client execution: {}", e);
+                let partial = verified.load(Ordering::Relaxed) > 0;
+                return Ok(ExitCode::from(exit_code(&e, partial)));
+            }
+        }
+        Commands::List { addr, key, json } => {
+            let client = Client::builder(addr, key).build();
+            match client.list().await {
+                Ok(metadata) => print_listing(&metadata, json),
+                Err(e) => {
+                    error!("Error during client execution: {}", e);
+                    return Ok(ExitCode::from(exit_code(&e, false)));
+                }
             };
         }
     };
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
+}
+
+fn exit_code(error: &ContegoError, partial: bool) -> u8 {
+    match error {
+        ContegoError::Auth | ContegoError::Verification => EXIT_AUTH,
+        ContegoError::Integrity(_) => EXIT_INTEGRITY,
+        _ if partial => EXIT_PARTIAL,
+        ContegoError::Network(_) => EXIT_NETWORK,
+        _ => EXIT_FAILURE,
+    }
+}
+
+fn print_listing(metadata: &[FileInfo], json: bool) {
+    if json {
+        let files = metadata
+            .iter()
+            .map(|f| json!({ "name": f.display_name(), "size": f.size, "hash": f.hash }))
+            .collect::<Vec<_>>();
+
+        println!("{}", Value::Array(files));
+        return;
+    }
+
+    for file in metadata {
+        println!(
+            "{:>10}  {}  {}",
+            human_size(file.size),
+            file.hash,
+            file.display_name()
+        );
+    }
+
+    let total = metadata.iter().map(|f| f.size).sum();
+    println!("{} files, {} total", metadata.len(), human_size(total));
 }
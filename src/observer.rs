@@ -0,0 +1,98 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use crate::{util::FileInfo, Error};
+
+/// Something that happened on a connection, emitted by both the client and the server.
+/// `peer` is the address of the other side.
+#[derive(Clone, Copy, Debug)]
+pub enum Event<'a> {
+    /// The TCP connection is open
+    Connected { peer: SocketAddr },
+    /// Key exchange and access key check succeeded
+    Authorized { peer: SocketAddr },
+    /// The manifest was received (client) or sent (server)
+    Manifest {
+        peer: SocketAddr,
+        files: &'a [FileInfo],
+    },
+    /// A chunk of `file` was transferred, `position` being the amount of bytes transferred so far
+    Chunk {
+        peer: SocketAddr,
+        file: &'a FileInfo,
+        position: u64,
+        size: usize,
+    },
+    /// The file was transferred completely and its hash matches
+    Verified {
+        peer: SocketAddr,
+        file: &'a FileInfo,
+    },
+    /// The transfer of the file was aborted
+    Failed {
+        peer: SocketAddr,
+        file: &'a FileInfo,
+        error: &'a Error,
+    },
+    /// The connection is closed, `error` is set if it ended abnormally, e.g. [`Error::Auth`] for a wrong key
+    Disconnected {
+        peer: SocketAddr,
+        error: Option<&'a Error>,
+    },
+}
+
+impl Event<'_> {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::Connected { .. } => "Connected",
+            Event::Authorized { .. } => "Authorized",
+            Event::Manifest { .. } => "Manifest",
+            Event::Chunk { .. } => "Chunk",
+            Event::Verified { .. } => "Verified",
+            Event::Failed { .. } => "Failed",
+            Event::Disconnected { .. } => "Disconnected",
+        }
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        match *self {
+            Event::Connected { peer }
+            | Event::Authorized { peer }
+            | Event::Manifest { peer, .. }
+            | Event::Chunk { peer, .. }
+            | Event::Verified { peer, .. }
+            | Event::Failed { peer, .. }
+            | Event::Disconnected { peer, .. } => peer,
+        }
+    }
+}
+
+/// Receives the events of a connection. Called inline on the connection's task, so
+/// implementations should return quickly and hand off heavier work, e.g. through a channel.
+pub trait Observer: Send + Sync {
+    fn event(&self, event: &Event<'_>);
+}
+
+impl<F> Observer for F
+where
+    F: Fn(&Event<'_>) + Send + Sync,
+{
+    fn event(&self, event: &Event<'_>) {
+        self(event)
+    }
+}
+
+/// The observers a client or server reports to.
+#[derive(Clone, Default)]
+pub struct Observers(Vec<Arc<dyn Observer>>);
+
+impl Observers {
+    pub fn add(&mut self, observer: impl Observer + 'static) {
+        self.0.push(Arc::new(observer));
+    }
+
+    pub(crate) fn emit(&self, event: Event<'_>) {
+        for observer in &self.0 {
+            observer.event(&event);
+        }
+    }
+}
@@ -2,9 +2,11 @@ use std::{
     env,
     io::{Error, ErrorKind::NotFound},
     net::{AddrParseError, SocketAddr},
+    num::NonZeroU64,
     path::PathBuf,
 };
 
+use glob::{Pattern, PatternError};
 use log::debug;
 
 pub fn addr_parser(addr: &str) -> Result<SocketAddr, AddrParseError> {
@@ -24,10 +26,10 @@ pub fn filepath_parser(path: &str) -> Result<PathBuf, Error> {
         .parse::<PathBuf>()
         .expect("Failed to parse path");
 
-    if path.exists() && path.is_file() {
+    if path.is_file() || path.is_dir() {
         Ok(path)
     } else {
-        Err(Error::new(NotFound, "File not found"))
+        Err(Error::new(NotFound, "File or directory not found"))
     }
 }
 
@@ -43,6 +45,38 @@ pub fn dirpath_parser(path: &str) -> Result<PathBuf, Error> {
     }
 }
 
+pub fn glob_parser(pattern: &str) -> Result<Pattern, PatternError> {
+    Pattern::new(pattern)
+}
+
+/// Parses a byte amount with an optional binary suffix (e.g. `512`, `64K`, `1.5G`).
+pub fn size_parser(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (num, multiplier) = match size.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&size[..size.len() - 1], 1u64 << 10),
+        Some('M') => (&size[..size.len() - 1], 1u64 << 20),
+        Some('G') => (&size[..size.len() - 1], 1u64 << 30),
+        Some('T') => (&size[..size.len() - 1], 1u64 << 40),
+        _ => (size, 1),
+    };
+
+    match num.trim().parse::<f64>() {
+        Ok(num) if num.is_finite() && num >= 0.0 => Ok((num * multiplier as f64) as u64),
+        _ => Err(format!("Invalid size '{}'", size)),
+    }
+}
+
+/// Parses a bandwidth limit in bytes per second like [`size_parser`], rejecting limits below 1 byte.
+pub fn rate_parser(rate: &str) -> Result<NonZeroU64, String> {
+    match NonZeroU64::new(size_parser(rate)?) {
+        Some(rate) => Ok(rate),
+        None => Err(format!(
+            "Rate limit '{}' is zero, omit the flag to transfer without a limit",
+            rate.trim()
+        )),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -64,6 +98,28 @@ mod test {
         );
     }
 
+    #[test]
+    fn valid_size() {
+        assert_eq!(size_parser("512").unwrap(), 512);
+        assert_eq!(size_parser("64k").unwrap(), 64 * 1024);
+        assert_eq!(size_parser("1.5M").unwrap(), 1536 * 1024);
+        assert_eq!(size_parser("2G").unwrap(), 2 * 1024 * 1024 * 1024);
+    }
+
+    #[test]
+    #[should_panic]
+    fn invalid_size() {
+        size_parser("-5M").unwrap();
+    }
+
+    #[test]
+    fn zero_rate() {
+        assert_eq!(rate_parser("10M").unwrap().get(), 10 * 1024 * 1024);
+        assert_eq!(rate_parser("1").unwrap(), NonZeroU64::MIN);
+        assert!(rate_parser("0").is_err());
+        assert!(rate_parser("0.1").is_err());
+    }
+
     #[test]
     #[should_panic]
     fn short_ip() {
@@ -0,0 +1,101 @@
+use std::io::{self, IsTerminal};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use crate::util::FileInfo;
+
+const TEMPLATE: &str =
+    "{msg:30!} [{bar:30}] {bytes:>10}/{total_bytes:<10} {bytes_per_sec:>12} ETA {eta}";
+
+/// Per-file and aggregate progress bars of the client, drawn to stdout.
+#[derive(Clone)]
+pub struct Progress {
+    bars: MultiProgress,
+    total: ProgressBar,
+}
+
+impl Progress {
+    /// Visible only when stdout is a terminal.
+    pub fn new() -> Self {
+        match io::stdout().is_terminal() {
+            true => Self::with_target(ProgressDrawTarget::stdout()),
+            false => Self::hidden(),
+        }
+    }
+
+    pub fn hidden() -> Self {
+        Self::with_target(ProgressDrawTarget::hidden())
+    }
+
+    fn with_target(target: ProgressDrawTarget) -> Self {
+        let bars = MultiProgress::with_draw_target(target);
+        let total = ProgressBar::hidden();
+
+        Self { bars, total }
+    }
+
+    /// Bars have to be suspended while logging to avoid mixing up the output.
+    pub fn bars(&self) -> &MultiProgress {
+        &self.bars
+    }
+
+    pub fn start(&mut self, files: &[FileInfo]) {
+        let size = files.iter().map(|f| f.size).sum();
+
+        self.total = self.bars.add(ProgressBar::new(size));
+        self.total.set_style(style());
+        self.total
+            .set_message(format!("Total ({} files)", files.len()));
+    }
+
+    /// Adds a bar for a single file, `offset` being the amount of bytes already on disk.
+    pub fn file(&self, file: &FileInfo, offset: u64) -> ProgressBar {
+        let bar = self
+            .bars
+            .insert_before(&self.total, ProgressBar::new(file.size));
+        bar.set_style(style());
+        bar.set_message(file.display_name().to_string());
+        bar.set_position(offset);
+        bar.reset_eta();
+
+        self.total.inc(offset);
+
+        bar
+    }
+
+    pub fn advance(&self, bar: &ProgressBar, amount: u64) {
+        bar.inc(amount);
+        self.total.inc(amount);
+    }
+
+    /// Takes back `amount` bytes from the total, e.g. when a file has to be downloaded again.
+    pub fn rewind(&self, amount: u64) {
+        self.total
+            .set_position(self.total.position().saturating_sub(amount));
+    }
+
+    pub fn skip(&self, file: &FileInfo) {
+        self.total.inc(file.size);
+    }
+
+    pub fn finish_file(&self, bar: &ProgressBar) {
+        bar.finish_and_clear();
+        self.bars.remove(bar);
+    }
+
+    pub fn finish(&self) {
+        self.total.finish();
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn style() -> ProgressStyle {
+    ProgressStyle::with_template(TEMPLATE)
+        .expect("progress template is valid")
+        .progress_chars("=> ")
+}
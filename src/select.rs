@@ -0,0 +1,181 @@
+use std::io::{self, BufRead, Write};
+
+use glob::Pattern;
+use log::{debug, info};
+
+use crate::{
+    util::{interact, FileInfo},
+    Error, Result,
+};
+
+/// Client-side filter deciding which manifest entries are requested from the server.
+#[derive(Clone, Debug, Default)]
+pub struct Selection {
+    pub names: Vec<String>,
+    pub include: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+    pub max_size: Option<u64>,
+    pub interactive: bool,
+}
+
+impl Selection {
+    /// Whether a file passes the explicit names, glob patterns and the size cap.
+    pub fn matches(&self, file: &FileInfo) -> bool {
+        let name = file.display_name();
+
+        if !self.names.is_empty() && !self.names.iter().any(|n| *n == name) {
+            return false;
+        }
+
+        if !self.include.is_empty() && !self.include.iter().any(|p| p.matches(&name)) {
+            return false;
+        }
+
+        if self.exclude.iter().any(|p| p.matches(&name)) {
+            return false;
+        }
+
+        match self.max_size {
+            Some(max_size) => file.size <= max_size,
+            None => true,
+        }
+    }
+
+    pub async fn apply(&self, metadata: Vec<FileInfo>) -> Result<Vec<FileInfo>> {
+        let total = metadata.len();
+        let mut selected = metadata
+            .into_iter()
+            .filter(|f| self.matches(f))
+            .collect::<Vec<_>>();
+
+        debug!("{} of {} files match the filters", selected.len(), total);
+
+        if self.interactive && !selected.is_empty() {
+            selected = interact(move || pick(selected)).await?;
+        }
+
+        info!("Selected {} of {} files", selected.len(), total);
+
+        Ok(selected)
+    }
+}
+
+fn pick(files: Vec<FileInfo>) -> Result<Vec<FileInfo>> {
+    let mut stdout = io::stdout();
+
+    for (i, file) in files.iter().enumerate() {
+        writeln!(
+            stdout,
+            "{:>4}  {} ({} bytes)",
+            i + 1,
+            file.display_name(),
+            file.size
+        )?;
+    }
+
+    write!(stdout, "Files to download (e.g. '1,3-5', empty for all): ")?;
+    stdout.flush()?;
+
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+
+    let picks = parse_picks(&line, files.len())?;
+
+    Ok(files
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| picks.contains(i))
+        .map(|(_, f)| f)
+        .collect())
+}
+
+/// Parses 1-based indices and inclusive ranges into 0-based indices, an empty input selects everything.
+pub fn parse_picks(input: &str, amt: usize) -> Result<Vec<usize>> {
+    let input = input.trim();
+
+    if input.is_empty() {
+        return Ok((0..amt).collect());
+    }
+
+    let mut picks = Vec::new();
+
+    for part in input.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let (start, end) = match part.split_once('-') {
+            Some((start, end)) => (index(start)?, index(end)?),
+            None => {
+                let i = index(part)?;
+                (i, i)
+            }
+        };
+
+        if start == 0 || end > amt || start > end {
+            return Err(Error::Config(format!(
+                "Invalid selection '{}' (files 1-{})",
+                part, amt
+            )));
+        }
+
+        picks.extend(start - 1..end);
+    }
+
+    picks.sort_unstable();
+    picks.dedup();
+
+    Ok(picks)
+}
+
+fn index(input: &str) -> Result<usize> {
+    match input.trim().parse() {
+        Ok(i) => Ok(i),
+        Err(_) => Err(Error::Config(format!(
+            "Invalid file number '{}'",
+            input.trim()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn file(name: &str, size: u64) -> FileInfo {
+        FileInfo::new(name, size, String::new())
+    }
+
+    #[test]
+    fn filters() {
+        let selection = Selection {
+            include: vec![Pattern::new("logs/*.log").unwrap()],
+            exclude: vec![Pattern::new("*debug*").unwrap()],
+            max_size: Some(100),
+            ..Default::default()
+        };
+
+        assert!(selection.matches(&file("logs/app.log", 100)));
+        assert!(!selection.matches(&file("logs/app.log", 101)));
+        assert!(!selection.matches(&file("logs/debug.log", 1)));
+        assert!(!selection.matches(&file("app.log", 1)));
+        assert!(Selection::default().matches(&file("anything", u64::MAX)));
+    }
+
+    #[test]
+    fn explicit_names() {
+        let selection = Selection {
+            names: vec![String::from("a.txt"), String::from("dir/b.txt")],
+            ..Default::default()
+        };
+
+        assert!(selection.matches(&file("a.txt", 1)));
+        assert!(selection.matches(&file("dir/b.txt", 1)));
+        assert!(!selection.matches(&file("b.txt", 1)));
+    }
+
+    #[test]
+    fn picks() {
+        assert_eq!(parse_picks("", 3).unwrap(), vec![0, 1, 2]);
+        assert_eq!(parse_picks("3, 1-2 ,2", 5).unwrap(), vec![0, 1, 2]);
+        assert!(parse_picks("0", 3).is_err());
+        assert!(parse_picks("2-4", 3).is_err());
+        assert!(parse_picks("x", 3).is_err());
+    }
+}
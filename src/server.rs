@@ -1,14 +1,45 @@
-use std::{collections::HashMap, error::Error, net::SocketAddr, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap,
+    io::{self, SeekFrom},
+    net::SocketAddr,
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use log::{debug, error, info};
+use clap::ValueEnum;
+use log::{debug, error, info, warn};
 use tokio::{
     fs::File,
-    io::AsyncReadExt,
+    io::{AsyncReadExt, AsyncSeekExt},
     net::{TcpListener, TcpStream},
-    sync::mpsc,
+    sync::{mpsc, Mutex, Semaphore},
+    time,
+};
+
+use crate::{
+    crypto::{CipherSuite, Crypto, RekeyLimit},
+    handshake::{self, Capabilities},
+    observer::{Event, Observer, Observers},
+    sockets::{Message, SocketHandler, MAX_FRAME_SIZE},
+    throttle::RateLimiter,
+    util::{confirm_code, human_size, interact, FileInfo},
+    Error, Result,
 };
 
-use crate::{crypto::Crypto, sockets::SocketHandler, util::FileInfo};
+// leaves room for the message tag and the encryption overhead within a frame
+const MAX_CHUNKSIZE: usize = MAX_FRAME_SIZE - 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What happens to new connections once the maximum amount of concurrent clients is reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LimitPolicy {
+    /// Keep the connection open until a slot frees up
+    Queue,
+    /// Close the connection with an error message
+    Reject,
+}
 
 #[derive(Clone)]
 pub struct Server {
@@ -17,184 +48,482 @@ pub struct Server {
     chunksize: usize,
     metadata: Vec<FileInfo>,
     index: HashMap<String, PathBuf>,
+    max_clients: usize,
+    limit_policy: LimitPolicy,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    client_rate_limit: Option<NonZeroU64>,
+    timeout: Option<Duration>,
+    handshake_timeout: Duration,
+    ciphers: Vec<CipherSuite>,
+    prefer_client_ciphers: bool,
+    rekey: RekeyLimit,
+    confirm_code: bool,
+    prompt: Arc<Mutex<()>>,
+    observers: Observers,
+}
+
+/// Configures a [`Server`], everything but the displayed address and the access key is optional.
+pub struct ServerBuilder {
+    server: Server,
+}
+
+impl ServerBuilder {
+    pub fn new(addr: SocketAddr, key: impl Into<String>) -> Self {
+        Self {
+            server: Server {
+                addr,
+                key: key.into(),
+                chunksize: 8192,
+                metadata: Vec::new(),
+                index: HashMap::new(),
+                max_clients: 8,
+                limit_policy: LimitPolicy::Queue,
+                rate_limiter: None,
+                client_rate_limit: None,
+                timeout: None,
+                handshake_timeout: HANDSHAKE_TIMEOUT,
+                ciphers: CipherSuite::all(),
+                prefer_client_ciphers: false,
+                rekey: RekeyLimit::default(),
+                confirm_code: false,
+                prompt: Arc::new(Mutex::new(())),
+                observers: Observers::default(),
+            },
+        }
+    }
+
+    /// Shared files as returned by [`util::metadata`](crate::util::metadata).
+    pub fn share(mut self, metadata: Vec<FileInfo>, index: HashMap<String, PathBuf>) -> Self {
+        self.server.metadata = metadata;
+        self.server.index = index;
+        self
+    }
+
+    /// Amount of file bytes per chunk message, 8192 by default and capped below the frame size limit.
+    pub fn chunksize(mut self, chunksize: usize) -> Self {
+        self.server.chunksize = chunksize.clamp(1, MAX_CHUNKSIZE);
+        self
+    }
+
+    /// Maximum amount of concurrently served clients, 8 by default.
+    pub fn max_clients(mut self, max_clients: usize) -> Self {
+        self.server.max_clients = max_clients.max(1);
+        self
+    }
+
+    pub fn limit_policy(mut self, limit_policy: LimitPolicy) -> Self {
+        self.server.limit_policy = limit_policy;
+        self
+    }
+
+    /// Total bandwidth limit in bytes per second shared by all clients, `None` for no limit.
+    pub fn rate_limit(mut self, rate_limit: Option<NonZeroU64>) -> Self {
+        self.server.rate_limiter = rate_limit.map(|rate| Arc::new(RateLimiter::new(rate)));
+        self
+    }
+
+    /// Bandwidth limit in bytes per second for each client, `None` for no limit.
+    pub fn client_rate_limit(mut self, client_rate_limit: Option<NonZeroU64>) -> Self {
+        self.server.client_rate_limit = client_rate_limit;
+        self
+    }
+
+    /// Limits every wait for a client, `None` waits indefinitely. Note that clients picking
+    /// their files interactively may take a while before sending the first request.
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.server.timeout = timeout;
+        self
+    }
+
+    /// Deadline for new clients to complete the hello and key exchange and send their key confirmation,
+    /// 10 seconds by default. Connections that don't authenticate in time give up their slot, the
+    /// verification code prompt doesn't count towards the deadline.
+    pub fn handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+        self.server.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    /// Cipher suites offered to clients, the first one supported by a client is used.
+    pub fn ciphers(mut self, ciphers: Vec<CipherSuite>) -> Self {
+        self.server.ciphers = ciphers;
+        self
+    }
+
+    /// Picks the first of the client's cipher suites offered by the server instead, e.g. to let
+    /// clients without hardware AES support choose ChaCha20-Poly1305.
+    pub fn prefer_client_ciphers(mut self, prefer_client_ciphers: bool) -> Self {
+        self.server.prefer_client_ciphers = prefer_client_ciphers;
+        self
+    }
+
+    /// Traffic after which the server switches to a new key, 1 GiB or 2^20 frames by default.
+    pub fn rekey(mut self, rekey: RekeyLimit) -> Self {
+        self.server.rekey = rekey;
+        self
+    }
+
+    /// Asks on the terminal whether the verification code matches the client's before authorizing.
+    /// Clients are asked about one at a time.
+    pub fn confirm_code(mut self, confirm_code: bool) -> Self {
+        self.server.confirm_code = confirm_code;
+        self
+    }
+
+    /// Adds an observer, can be called multiple times.
+    pub fn observer(mut self, observer: impl Observer + 'static) -> Self {
+        self.server.observers.add(observer);
+        self
+    }
+
+    pub fn build(self) -> Arc<Server> {
+        Arc::new(self.server)
+    }
 }
 
 impl Server {
-    pub fn new(
-        addr: SocketAddr,
-        key: String,
-        chunksize: usize,
-        metadata: Vec<FileInfo>,
-        index: HashMap<String, PathBuf>,
-    ) -> Arc<Self> {
-        Arc::new(Self {
-            addr,
-            key,
-            chunksize,
-            metadata,
-            index,
-        })
+    pub fn builder(addr: SocketAddr, key: impl Into<String>) -> ServerBuilder {
+        ServerBuilder::new(addr, key)
     }
 
     pub async fn start(
         self: Arc<Self>,
-        mut kill: mpsc::Receiver<()>,
+        kill: mpsc::Receiver<()>,
         bind_addr: &SocketAddr,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+    ) -> Result<()> {
+        let listener = TcpListener::bind(bind_addr).await.map_err(Error::Network)?;
+
+        self.serve(kill, listener).await
+    }
+
+    /// Serves clients on an already bound listener until a message is sent over `kill`.
+    pub async fn serve(
+        self: Arc<Self>,
+        mut kill: mpsc::Receiver<()>,
+        listener: TcpListener,
+    ) -> Result<()> {
         tokio::select! {
-            _ = self.listen(bind_addr) => Ok(()),
+            result = self.listen(listener) => result,
             _ = kill.recv() => Ok(()),
         }
     }
 
-    async fn listen(
-        self: Arc<Self>,
-        bind_addr: &SocketAddr,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let listener = TcpListener::bind(bind_addr).await?;
-
+    async fn listen(self: Arc<Self>, listener: TcpListener) -> Result<()> {
         info!("Listening on {} - Access key: {}", self.addr, self.key);
 
+        let slots = Arc::new(Semaphore::new(self.max_clients));
+
         loop {
             let this_self = self.clone();
-            let (mut socket, addr) = listener.accept().await?;
+            let (mut socket, addr) = listener.accept().await.map_err(Error::Network)?;
 
             info!("New client connected: {}", addr);
 
-            match tokio::spawn(async move { this_self.connection(&mut socket, &addr).await }).await
-            {
-                Ok(_) => info!("Client disconnected: {}", addr),
-                Err(e) => error!("Fatal error in connection {}: {}", addr, e),
+            let permit = match (slots.clone().try_acquire_owned(), self.limit_policy) {
+                (Ok(permit), _) => Some(permit),
+                (Err(_), LimitPolicy::Queue) => None,
+                (Err(_), LimitPolicy::Reject) => {
+                    warn!("({}): Connection limit reached, rejecting", addr);
+                    tokio::spawn(async move { Self::reject(&mut socket).await });
+                    continue;
+                }
             };
+
+            let slots = slots.clone();
+
+            tokio::spawn(async move {
+                let _permit = match permit {
+                    Some(permit) => permit,
+                    None => {
+                        info!("({}): Connection limit reached, queued", addr);
+                        slots
+                            .acquire_owned()
+                            .await
+                            .expect("semaphore is never closed")
+                    }
+                };
+
+                match this_self.connection(&mut socket, &addr).await {
+                    Ok(_) => info!("Client disconnected: {}", addr),
+                    Err(e) => error!("Error in connection {}: {}", addr, e),
+                };
+            });
         }
     }
 
-    async fn connection(
-        &self,
-        socket: &mut TcpStream,
-        addr: &SocketAddr,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+    async fn reject(socket: &mut TcpStream) {
+        let mut handler = SocketHandler::new(socket);
+        let msg = Message::Error(String::from("Server is busy, try again later"));
+
+        if let Err(e) = handler.send_message(&msg).await {
+            debug!("Failed to notify rejected client: {}", e);
+        }
+    }
+
+    async fn connection(&self, socket: &mut TcpStream, addr: &SocketAddr) -> Result<()> {
+        self.observers.emit(Event::Connected { peer: *addr });
+
+        let result = self.session(socket, addr).await;
+
+        self.observers.emit(Event::Disconnected {
+            peer: *addr,
+            error: result.as_ref().err(),
+        });
+
+        result
+    }
+
+    async fn session(&self, socket: &mut TcpStream, addr: &SocketAddr) -> Result<()> {
         let mut handler = SocketHandler::new(socket);
-        let crypto = Crypto::new(&mut handler, false).await?;
+        handler.set_timeout(self.timeout);
+
+        if let Some(limiter) = &self.rate_limiter {
+            handler.add_limiter(limiter.clone());
+        }
+
+        if let Some(rate) = self.client_rate_limit {
+            handler.add_limiter(Arc::new(RateLimiter::new(rate)));
+        }
+
+        // idle or unauthenticated connections would otherwise hold on to their slot indefinitely
+        let (crypto, tag) =
+            match time::timeout(self.handshake_timeout, self.handshake(&mut handler)).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    let reason = format!(
+                        "Client didn't authenticate within {} ms",
+                        self.handshake_timeout.as_millis()
+                    );
+                    return Err(Error::Network(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        reason,
+                    )));
+                }
+            };
+
+        info!("({}): Verification code: {}", addr, crypto.code());
+
+        if !self.authorize(&mut handler, &crypto, tag, addr).await? {
+            return Err(Error::Auth);
+        }
+
         handler.set_crypto(crypto);
 
         debug!("({}): Connection established", addr);
+        self.observers.emit(Event::Authorized { peer: *addr });
 
-        if !self.authorize(&mut handler, addr).await? {
-            info!("({}): Invalid access key", addr);
-            return Ok(());
-        }
+        let start = Instant::now();
 
         self.metadata(&mut handler, addr).await?;
-        self.requests(&mut handler, addr).await?;
+        let (files, bytes) = self.requests(&mut handler, addr).await?;
+
+        let elapsed = start.elapsed();
+        let throughput = (bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64;
+
+        info!(
+            "({}): Sent {} files, {} in {:.1} s ({}/s)",
+            addr,
+            files,
+            human_size(bytes),
+            elapsed.as_secs_f64(),
+            human_size(throughput)
+        );
 
         Ok(())
     }
 
+    /// Runs the hello exchange and key exchange, returning the keys along with the client's confirmation tag.
+    async fn handshake(&self, handler: &mut SocketHandler<'_>) -> Result<(Crypto, Vec<u8>)> {
+        let capabilities = Capabilities {
+            ciphers: self.ciphers.clone(),
+            prefer_client_ciphers: self.prefer_client_ciphers,
+            ..Default::default()
+        };
+
+        let session = handshake::hello(handler, capabilities, false).await?;
+        let mut crypto = Crypto::new(handler, &self.key, false, &session).await?;
+        crypto.set_rekey_limit(self.rekey);
+
+        let tag = match handler.recv_message().await? {
+            Message::Auth(tag) => tag,
+            other => return Err(other.unexpected("Auth")),
+        };
+
+        Ok((crypto, tag))
+    }
+
     async fn authorize(
         &self,
         handler: &mut SocketHandler<'_>,
+        crypto: &Crypto,
+        tag: Vec<u8>,
         addr: &SocketAddr,
-    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    ) -> Result<bool> {
         debug!("({}): Starting authorization", addr);
 
-        let buf = handler.recv().await?;
-        let key = String::from_utf8(buf)?;
-
-        let is_valid: bool;
-        let res_msg: Vec<u8>;
+        let is_valid = crypto.verify_confirmation(true, &tag);
 
-        if key != self.key {
-            is_valid = false;
-            res_msg = b"DISCONNECT".to_vec();
-        } else {
-            is_valid = true;
-            res_msg = b"VALID".to_vec();
+        // only clients with the right key get to the prompt, the client's tag is read first
+        // so that closing the connection afterwards doesn't discard it and reset the socket
+        if is_valid && self.confirm_code && !self.confirm(crypto, addr).await? {
+            handler.send_message(&Message::CodeRejected).await?;
+            return Err(Error::Verification);
         }
 
-        handler.send(&res_msg).await?;
+        // the server proves knowledge of the key in turn, so the client doesn't trust a bare result
+        let reply = match is_valid {
+            true => Some(crypto.confirmation(false)),
+            false => None,
+        };
+        handler.send_message(&Message::AuthResult(reply)).await?;
 
         debug!("({}): Authorization finished", addr);
 
         Ok(is_valid)
     }
 
-    async fn metadata(
-        &self,
-        handler: &mut SocketHandler<'_>,
-        addr: &SocketAddr,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        debug!("({}): Starting to send metadata", addr);
-
-        let amt = self.metadata.len();
-        let msg = amt.to_string().as_bytes().to_vec();
+    /// Prompts for the client's verification code, one client at a time.
+    async fn confirm(&self, crypto: &Crypto, addr: &SocketAddr) -> Result<bool> {
+        let _prompt = self.prompt.lock().await;
+        let (peer, code) = (*addr, crypto.code());
 
-        handler.send(&msg).await?;
-
-        let buf = handler.recv().await?;
-        let res_amt = String::from_utf8(buf)?.trim().parse::<usize>()?;
-
-        if res_amt != amt {
-            return Err("Broken message sequence during metadata exchange".into());
-        }
+        interact(move || confirm_code(&peer, &code)).await
+    }
 
-        debug!("({}): Metadata amount confirmed successfully", addr);
+    async fn metadata(&self, handler: &mut SocketHandler<'_>, addr: &SocketAddr) -> Result<()> {
+        debug!("({}): Starting to send metadata", addr);
 
-        for file in &self.metadata {
-            let msg = format!("{}:{}:{}", file.name, file.size, file.hash)
-                .as_bytes()
-                .to_vec();
-            handler.send(&msg).await?;
+        handler
+            .send_message(&Message::Manifest(self.metadata.clone()))
+            .await?;
 
-            debug!("({}): Sent metadata of file '{}'", addr, file.hash);
-        }
+        debug!("({}): Sent metadata of {} files", addr, self.metadata.len());
+        self.observers.emit(Event::Manifest {
+            peer: *addr,
+            files: &self.metadata,
+        });
 
         Ok(())
     }
 
+    /// Serves file requests until the client says goodbye, returns the amount of files and bytes sent.
     async fn requests(
         &self,
         handler: &mut SocketHandler<'_>,
         addr: &SocketAddr,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+    ) -> Result<(usize, u64)> {
         debug!("({}): Waiting for file requests", addr);
 
+        let mut files = 0;
+        let mut bytes = 0;
+
         loop {
-            let buf = handler.recv().await?;
-            let hash = String::from_utf8(buf)?;
-            let hash = hash.trim();
+            let (hash, offset) = match handler.recv_message().await? {
+                Message::Request { hash, offset } => (hash, offset),
+                Message::Bye => break,
+                other => return Err(other.unexpected("Request")),
+            };
 
-            if hash == "DISCONNECT" {
-                break;
+            debug!("({}): Received request for file '{}'", addr, hash);
+
+            let (file, path) = match (
+                self.metadata.iter().find(|f| f.hash == hash),
+                self.index.get(&hash),
+            ) {
+                (Some(file), Some(path)) => (file, path),
+                _ => {
+                    let reason = format!("Unknown file '{}'", hash);
+                    handler.send_message(&Message::Error(reason)).await?;
+                    continue;
+                }
+            };
+
+            match self.send_file(handler, addr, file, path, offset).await {
+                Ok(Some(sent)) => bytes += sent,
+                Ok(None) => continue,
+                Err(e) => {
+                    self.observers.emit(Event::Failed {
+                        peer: *addr,
+                        file,
+                        error: &e,
+                    });
+                    return Err(e);
+                }
             }
 
-            debug!("({}): Received request for file '{}'", addr, hash);
+            files += 1;
 
-            let mut file = File::open(self.index[hash].clone()).await?;
-            let mut remaining = file.metadata().await?.len();
-            let mut sendbuf = vec![0u8; self.chunksize];
+            debug!("({}): File '{}' successfully transferred", addr, hash);
+            self.observers.emit(Event::Verified { peer: *addr, file });
+        }
 
-            debug!("({}): Sending bytes of '{}'", addr, hash);
+        Ok((files, bytes))
+    }
 
-            while remaining != 0 {
-                let n = file.read(&mut sendbuf).await?;
-                handler.send(&sendbuf[..n]).await?;
-                remaining -= n as u64;
+    /// Sends a file from `offset` on and waits for the client's confirmation, returns the amount of
+    /// bytes sent or `None` if the request was invalid or the resumed file didn't match.
+    async fn send_file(
+        &self,
+        handler: &mut SocketHandler<'_>,
+        addr: &SocketAddr,
+        info: &FileInfo,
+        path: &Path,
+        offset: u64,
+    ) -> Result<Option<u64>> {
+        let mut file = File::open(path).await?;
+        let size = file.metadata().await?.len();
+
+        if offset > size {
+            let reason = format!(
+                "Offset {} is beyond the end of file '{}'",
+                offset, info.hash
+            );
+            handler.send_message(&Message::Error(reason)).await?;
+            return Ok(None);
+        }
 
-                debug!("({}): {} bytes remaining", addr, remaining);
-            }
+        file.seek(SeekFrom::Start(offset)).await?;
 
-            let buf = handler.recv().await?;
-            let confirmation = String::from_utf8(buf)?;
-            let confirmation = confirmation.trim();
+        let mut remaining = size - offset;
+        let mut sendbuf = vec![0u8; self.chunksize];
 
-            if confirmation != hash {
-                return Err("Unsuccessful file transfer, hashes don't match".into());
-            }
+        debug!("({}): Sending bytes of '{}'", addr, info.hash);
 
-            debug!("({}): File '{}' successfully transferred", addr, hash);
+        while remaining != 0 {
+            let n = file.read(&mut sendbuf).await?;
+            let msg = Message::Chunk(sendbuf[..n].to_vec());
+            handler.send_message(&msg).await?;
+            remaining -= n as u64;
+
+            debug!("({}): {} bytes remaining", addr, remaining);
+            self.observers.emit(Event::Chunk {
+                peer: *addr,
+                file: info,
+                position: size - remaining,
+                size: n,
+            });
         }
 
-        Ok(())
+        let confirmation = match handler.recv_message().await? {
+            Message::Ack(confirmation) => confirmation,
+            other => return Err(other.unexpected("Ack")),
+        };
+
+        // stale data in front of a resumed file, the client requests it again from the start
+        if confirmation != info.hash && offset > 0 {
+            warn!(
+                "({}): Resumed file '{}' didn't match on the client",
+                addr, info.hash
+            );
+            return Ok(None);
+        }
+
+        if confirmation != info.hash {
+            return Err(Error::Integrity(format!(
+                "Client reported a different hash for file '{}'",
+                info.hash
+            )));
+        }
+
+        Ok(Some(size - offset))
     }
 }
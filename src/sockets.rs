@@ -1,21 +1,382 @@
-use std::error::Error;
+use std::{fmt, io, sync::Arc, time::Duration};
 
-use base64::{engine::general_purpose, Engine};
 use log::debug;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
     net::{
         tcp::{ReadHalf, WriteHalf},
         TcpStream,
     },
+    time,
 };
 
-use crate::crypto::Crypto;
+use crate::{
+    crypto::{CipherSuite, Crypto},
+    handshake::{Capabilities, Compression, Hello},
+    throttle::RateLimiter,
+    util::FileInfo,
+    Error,
+};
+
+const FRAME_HEADER_SIZE: usize = 4;
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+const HELLO_MAGIC: &[u8; 4] = b"CTGO";
+const FLAG_RESUME: u32 = 1;
+const FLAG_PREFER_CLIENT_CIPHERS: u32 = 1 << 1;
+
+// manifest entries are encoded as tag-length-value fields,
+// unknown tags are skipped to leave room for new per-file fields
+const FIELD_NAME: u8 = 1;
+const FIELD_SIZE: u8 = 2;
+const FIELD_HASH: u8 = 3;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MessageError {
+    Empty,
+    UnknownTag(u8),
+    Truncated,
+    InvalidUtf8,
+    TrailingBytes(usize),
+    InvalidHello,
+    MissingField(&'static str),
+    InvalidField(&'static str),
+    Unexpected {
+        expected: &'static str,
+        found: &'static str,
+    },
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::Empty => write!(f, "Received an empty message"),
+            MessageError::UnknownTag(tag) => write!(f, "Unknown message tag {:#04x}", tag),
+            MessageError::Truncated => write!(f, "Message ended unexpectedly"),
+            MessageError::InvalidUtf8 => write!(f, "Message contains invalid UTF-8"),
+            MessageError::TrailingBytes(n) => write!(f, "Message has {} trailing bytes", n),
+            MessageError::InvalidHello => write!(f, "Peer is not a contego instance"),
+            MessageError::MissingField(field) => write!(f, "Manifest entry without {}", field),
+            MessageError::InvalidField(field) => write!(f, "Manifest entry has invalid {}", field),
+            MessageError::Unexpected { expected, found } => {
+                write!(f, "Expected {} message, received {}", expected, found)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message {
+    Hello(Hello),
+    Auth(Vec<u8>),
+    AuthResult(Option<Vec<u8>>),
+    Manifest(Vec<FileInfo>),
+    Request { hash: String, offset: u64 },
+    Chunk(Vec<u8>),
+    Ack(String),
+    Error(String),
+    Bye,
+    CodeRejected,
+}
+
+impl Message {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Hello(_) => "Hello",
+            Message::Auth(_) => "Auth",
+            Message::AuthResult(_) => "AuthResult",
+            Message::Manifest(_) => "Manifest",
+            Message::Request { .. } => "Request",
+            Message::Chunk(_) => "Chunk",
+            Message::Ack(_) => "Ack",
+            Message::Error(_) => "Error",
+            Message::Bye => "Bye",
+            Message::CodeRejected => "CodeRejected",
+        }
+    }
+
+    /// Converts a message received out of sequence into an error, preserving the reason sent by the peer
+    /// and mapping a rejected verification code to [`Error::Verification`].
+    pub fn unexpected(self, expected: &'static str) -> Error {
+        match self {
+            Message::Error(reason) => Error::Peer(reason),
+            Message::CodeRejected => Error::Verification,
+            other => Error::Message(MessageError::Unexpected {
+                expected,
+                found: other.name(),
+            }),
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut enc = Encoder::default();
+
+        match self {
+            Message::Hello(hello) => {
+                // magic + version fields must stay stable between protocol versions,
+                // so that even incompatible peers are able to tell each other apart
+                let caps = &hello.capabilities;
+                enc.u8(0);
+                enc.raw(HELLO_MAGIC);
+                enc.u16(hello.version);
+                enc.u16(hello.min_version);
+                enc.u8(caps.ciphers.len() as u8);
+                caps.ciphers.iter().for_each(|c| enc.u8(c.id()));
+                enc.u8(caps.compression.len() as u8);
+                caps.compression.iter().for_each(|c| enc.u8(c.id()));
+                let mut flags = 0;
+                if caps.resume {
+                    flags |= FLAG_RESUME;
+                }
+                if caps.prefer_client_ciphers {
+                    flags |= FLAG_PREFER_CLIENT_CIPHERS;
+                }
+                enc.u32(flags);
+            }
+            Message::Auth(tag) => {
+                enc.u8(1);
+                enc.bytes(tag);
+            }
+            Message::AuthResult(tag) => {
+                // the server's confirmation tag, missing when the client's tag was rejected
+                enc.u8(2);
+                match tag {
+                    Some(tag) => {
+                        enc.u8(1);
+                        enc.bytes(tag);
+                    }
+                    None => enc.u8(0),
+                }
+            }
+            Message::Manifest(files) => {
+                enc.u8(3);
+                enc.u32(files.len() as u32);
+
+                for file in files {
+                    enc.u16(3);
+                    enc.u8(FIELD_NAME);
+                    enc.bytes(&file.name);
+                    enc.u8(FIELD_SIZE);
+                    enc.bytes(&file.size.to_be_bytes());
+                    enc.u8(FIELD_HASH);
+                    enc.str(&file.hash);
+                }
+            }
+            Message::Request { hash, offset } => {
+                enc.u8(4);
+                enc.str(hash);
+                enc.u64(*offset);
+            }
+            Message::Chunk(data) => {
+                enc.u8(5);
+                enc.raw(data);
+            }
+            Message::Ack(hash) => {
+                enc.u8(6);
+                enc.str(hash);
+            }
+            Message::Error(reason) => {
+                enc.u8(7);
+                enc.str(reason);
+            }
+            Message::Bye => enc.u8(8),
+            Message::CodeRejected => enc.u8(9),
+        }
+
+        enc.buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<Self, MessageError> {
+        let (&tag, rest) = buf.split_first().ok_or(MessageError::Empty)?;
+        let mut dec = Decoder { buf: rest };
+
+        let msg = match tag {
+            0 => {
+                if dec.raw(HELLO_MAGIC.len()).ok() != Some(HELLO_MAGIC.as_slice()) {
+                    return Err(MessageError::InvalidHello);
+                }
+
+                let version = dec.u16()?;
+                let min_version = dec.u16()?;
+
+                // unknown ids are dropped, which leaves only the features both builds understand
+                let n = dec.u8()? as usize;
+                let ciphers = dec.raw(n)?;
+                let ciphers = ciphers.iter().filter_map(|id| CipherSuite::from_id(*id));
+                let ciphers = ciphers.collect();
+
+                let n = dec.u8()? as usize;
+                let compression = dec.raw(n)?;
+                let compression = compression
+                    .iter()
+                    .filter_map(|id| Compression::from_id(*id));
+                let compression = compression.collect();
+
+                let flags = dec.u32()?;
+
+                Message::Hello(Hello {
+                    version,
+                    min_version,
+                    capabilities: Capabilities {
+                        ciphers,
+                        compression,
+                        resume: flags & FLAG_RESUME != 0,
+                        prefer_client_ciphers: flags & FLAG_PREFER_CLIENT_CIPHERS != 0,
+                    },
+                })
+            }
+            1 => Message::Auth(dec.bytes()?.to_vec()),
+            2 => match dec.u8()? {
+                0 => Message::AuthResult(None),
+                _ => Message::AuthResult(Some(dec.bytes()?.to_vec())),
+            },
+            3 => {
+                let amt = dec.u32()?;
+                let mut files = Vec::new();
+
+                for _ in 0..amt {
+                    let (mut name, mut size, mut hash) = (None, None, None);
+
+                    for _ in 0..dec.u16()? {
+                        let field = dec.u8()?;
+                        let value = dec.bytes()?;
+
+                        match field {
+                            FIELD_NAME => name = Some(value.to_vec()),
+                            FIELD_SIZE => match value.try_into() {
+                                Ok(value) => size = Some(u64::from_be_bytes(value)),
+                                Err(_) => return Err(MessageError::InvalidField("size")),
+                            },
+                            FIELD_HASH => match String::from_utf8(value.to_vec()) {
+                                Ok(value) => hash = Some(value),
+                                Err(_) => return Err(MessageError::InvalidField("hash")),
+                            },
+                            _ => {}
+                        }
+                    }
+
+                    let name = name.ok_or(MessageError::MissingField("name"))?;
+                    let size = size.ok_or(MessageError::MissingField("size"))?;
+                    let hash = hash.ok_or(MessageError::MissingField("hash"))?;
+
+                    files.push(FileInfo::new(name, size, hash));
+                }
+
+                Message::Manifest(files)
+            }
+            4 => Message::Request {
+                hash: dec.str()?,
+                offset: dec.u64()?,
+            },
+            5 => Message::Chunk(dec.rest().to_vec()),
+            6 => Message::Ack(dec.str()?),
+            7 => Message::Error(dec.str()?),
+            8 => Message::Bye,
+            9 => Message::CodeRejected,
+            tag => return Err(MessageError::UnknownTag(tag)),
+        };
+
+        match dec.buf.len() {
+            0 => Ok(msg),
+            n => Err(MessageError::TrailingBytes(n)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Encoder {
+    buf: Vec<u8>,
+}
+
+impl Encoder {
+    fn u8(&mut self, val: u8) {
+        self.buf.push(val);
+    }
+
+    fn u16(&mut self, val: u16) {
+        self.buf.extend_from_slice(&val.to_be_bytes());
+    }
+
+    fn u32(&mut self, val: u32) {
+        self.buf.extend_from_slice(&val.to_be_bytes());
+    }
+
+    fn u64(&mut self, val: u64) {
+        self.buf.extend_from_slice(&val.to_be_bytes());
+    }
+
+    fn raw(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    fn bytes(&mut self, data: &[u8]) {
+        self.u32(data.len() as u32);
+        self.raw(data);
+    }
+
+    fn str(&mut self, val: &str) {
+        self.bytes(val.as_bytes());
+    }
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn raw(&mut self, n: usize) -> Result<&'a [u8], MessageError> {
+        if self.buf.len() < n {
+            return Err(MessageError::Truncated);
+        }
+
+        let (data, rest) = self.buf.split_at(n);
+        self.buf = rest;
+
+        Ok(data)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let data = self.buf;
+        self.buf = &[];
+
+        data
+    }
+
+    fn u8(&mut self) -> Result<u8, MessageError> {
+        Ok(self.raw(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, MessageError> {
+        Ok(u16::from_be_bytes(self.raw(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, MessageError> {
+        Ok(u32::from_be_bytes(self.raw(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, MessageError> {
+        Ok(u64::from_be_bytes(self.raw(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], MessageError> {
+        let len = self.u32()? as usize;
+        self.raw(len)
+    }
+
+    fn str(&mut self) -> Result<String, MessageError> {
+        let data = self.bytes()?;
+
+        String::from_utf8(data.to_vec()).map_err(|_| MessageError::InvalidUtf8)
+    }
+}
 
 pub struct SocketHandler<'a> {
     writer: BufWriter<WriteHalf<'a>>,
     reader: BufReader<ReadHalf<'a>>,
     crypto: Option<Crypto>,
+    limiters: Vec<Arc<RateLimiter>>,
+    timeout: Option<Duration>,
 }
 
 impl<'a> SocketHandler<'a> {
@@ -28,48 +389,79 @@ impl<'a> SocketHandler<'a> {
             writer,
             reader,
             crypto: None,
+            limiters: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    /// Limits how long to wait for the peer's next frame, `None` waits indefinitely.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Throttles all traffic passing through the handler, multiple limiters apply simultaneously.
+    pub fn add_limiter(&mut self, limiter: Arc<RateLimiter>) {
+        self.limiters.push(limiter);
+    }
+
+    async fn throttle(&self, amount: usize) {
+        for limiter in &self.limiters {
+            limiter.acquire(FRAME_HEADER_SIZE + amount).await;
         }
     }
 
     pub fn set_crypto(&mut self, crypto: Crypto) {
-        // setting up AES cipher requires DH key exchange in plaintext,
+        // setting up AES cipher requires the key exchange & confirmation in plaintext,
         // meaning crypto can't be initialized at the same time as the socket handler
         debug!("Cryptography module initialized to the connection");
         self.crypto = Some(crypto);
     }
 
-    pub async fn send(&mut self, data: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+    pub async fn send(&mut self, data: &[u8]) -> Result<(), Error> {
         let data = match &mut self.crypto {
             Some(c) => c.encrypt(data).await?,
-            None => data.to_vec(), // syntactic sugar, never actually called
+            // the handshake messages are sent in plaintext until set_crypto is called
+            None => data.to_vec(),
         };
 
-        let mut encoded = general_purpose::STANDARD_NO_PAD
-            .encode(data)
-            .as_bytes()
-            .to_vec();
-        encoded.push(b':');
-
-        self.send_raw(&encoded).await?;
+        self.send_raw(&data).await?;
 
         Ok(())
     }
 
-    pub async fn send_raw(&mut self, data: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
-        self.writer.write_all(data).await?;
-        self.writer.flush().await?;
+    pub async fn send_message(&mut self, msg: &Message) -> Result<(), Error> {
+        debug!("Sending {} message", msg.name());
+
+        self.send(&msg.to_bytes()).await
+    }
+
+    pub async fn send_raw(&mut self, data: &[u8]) -> Result<(), Error> {
+        if data.len() > MAX_FRAME_SIZE {
+            return Err(Error::Protocol(format!(
+                "Frame of {} bytes exceeds the maximum frame size ({} bytes)",
+                data.len(),
+                MAX_FRAME_SIZE
+            )));
+        }
+
+        self.throttle(data.len()).await;
+
+        // frame = 4 byte big-endian payload length + payload
+        let header = (data.len() as u32).to_be_bytes();
+
+        self.write_frame(&header, data)
+            .await
+            .map_err(Error::Network)?;
 
         debug!("Sent {} bytes to the socket", data.len());
 
         Ok(())
     }
 
-    pub async fn recv(&mut self) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
-        let mut buf = self.recv_raw(1).await?;
-        buf.pop();
-        buf = general_purpose::STANDARD_NO_PAD.decode(&buf)?.to_vec();
+    pub async fn recv(&mut self) -> Result<Vec<u8>, Error> {
+        let buf = self.recv_raw().await?;
 
-        let data = match &self.crypto {
+        let data = match &mut self.crypto {
             Some(c) => c.decrypt(&buf).await?,
             None => buf,
         };
@@ -77,27 +469,164 @@ impl<'a> SocketHandler<'a> {
         Ok(data)
     }
 
-    pub async fn recv_raw(
-        &mut self,
-        min_limit: usize,
-    ) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
-        let mut buf = Vec::new();
+    pub async fn recv_message(&mut self) -> Result<Message, Error> {
+        let buf = self.recv().await?;
+        let msg = Message::from_bytes(&buf)?;
 
-        while buf.len() <= min_limit {
-            let n = self.reader.read_until(b':', &mut buf).await?;
+        debug!("Received {} message", msg.name());
 
-            if n == 0 {
-                return Err("Received 0 bytes from the socket".into());
-            }
-        }
+        Ok(msg)
+    }
 
-        /*
-        TODO: use min_limit to check whether read_until has reached EOF before reading all the necessary bytes
-        (e.g. regarding ecdh key exchange) --> loop and read until buf.len() == min_limit
-        */
+    pub async fn recv_raw(&mut self) -> Result<Vec<u8>, Error> {
+        let buf = match self.timeout {
+            Some(limit) => match time::timeout(limit, self.read_frame()).await {
+                Ok(buf) => buf?,
+                Err(_) => {
+                    let reason = format!("No response from the peer in {} ms", limit.as_millis());
+                    return Err(Error::Network(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        reason,
+                    )));
+                }
+            },
+            None => self.read_frame().await?,
+        };
+
+        // throttling happens outside of the timeout, it's not the peer's fault
+        self.throttle(buf.len()).await;
 
         debug!("Received {} bytes from the socket", buf.len());
 
         Ok(buf)
     }
+
+    async fn read_frame(&mut self) -> Result<Vec<u8>, Error> {
+        let mut header = [0u8; FRAME_HEADER_SIZE];
+
+        self.reader
+            .read_exact(&mut header)
+            .await
+            .map_err(Error::Network)?;
+
+        let len = u32::from_be_bytes(header) as usize;
+
+        if len > MAX_FRAME_SIZE {
+            return Err(Error::Protocol(format!(
+                "Received frame of {} bytes exceeds the maximum frame size ({} bytes)",
+                len, MAX_FRAME_SIZE
+            )));
+        }
+
+        let mut buf = vec![0u8; len];
+        self.reader
+            .read_exact(&mut buf)
+            .await
+            .map_err(Error::Network)?;
+
+        Ok(buf)
+    }
+
+    async fn write_frame(&mut self, header: &[u8], data: &[u8]) -> Result<(), io::Error> {
+        self.writer.write_all(header).await?;
+        self.writer.write_all(data).await?;
+        self.writer.flush().await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn roundtrip(msg: Message) {
+        let parsed = Message::from_bytes(&msg.to_bytes()).unwrap();
+        assert_eq!(parsed, msg);
+    }
+
+    #[test]
+    fn message_roundtrip() {
+        roundtrip(Message::Hello(Hello::new(Capabilities::default())));
+        roundtrip(Message::Hello(Hello::new(Capabilities {
+            prefer_client_ciphers: true,
+            ..Default::default()
+        })));
+        roundtrip(Message::Auth(vec![1, 2, 3]));
+        roundtrip(Message::AuthResult(Some(vec![4, 5, 6])));
+        roundtrip(Message::AuthResult(None));
+        roundtrip(Message::Manifest(vec![
+            FileInfo::new("a:b.txt", 12, String::from("ab12")),
+            FileInfo::new(b"dir/\xff.bin".to_vec(), 1, String::from("cd34")),
+        ]));
+        roundtrip(Message::Request {
+            hash: String::from("ab12"),
+            offset: 4,
+        });
+        roundtrip(Message::Chunk(vec![0, 1, 2, 255]));
+        roundtrip(Message::Ack(String::from("ab12")));
+        roundtrip(Message::Error(String::from("reason")));
+        roundtrip(Message::Bye);
+        roundtrip(Message::CodeRejected);
+    }
+
+    #[test]
+    fn unknown_hello_features_ignored() {
+        let mut buf = vec![0];
+        buf.extend_from_slice(HELLO_MAGIC);
+        buf.extend_from_slice(&[0, 2, 0, 1]);
+        buf.extend_from_slice(&[2, CipherSuite::Aes256Gcm.id(), 0xee]);
+        buf.extend_from_slice(&[2, 0xee, Compression::None.id()]);
+        buf.extend_from_slice(&[0x80, 0, 0, 0]);
+
+        let hello = match Message::from_bytes(&buf).unwrap() {
+            Message::Hello(hello) => hello,
+            _ => unreachable!(),
+        };
+
+        assert_eq!(hello.version, 2);
+        assert_eq!(hello.capabilities.ciphers, vec![CipherSuite::Aes256Gcm]);
+        assert_eq!(hello.capabilities.compression, vec![Compression::None]);
+        assert!(!hello.capabilities.resume);
+    }
+
+    #[test]
+    fn unknown_manifest_fields_ignored() {
+        let mut buf = vec![3, 0, 0, 0, 1, 0, 4];
+        buf.extend_from_slice(&[0x7f, 0, 0, 0, 2, 0xaa, 0xbb]);
+        buf.extend_from_slice(&[FIELD_HASH, 0, 0, 0, 2, b'a', b'b']);
+        buf.extend_from_slice(&[FIELD_SIZE, 0, 0, 0, 8, 0, 0, 0, 0, 0, 0, 0, 9]);
+        buf.extend_from_slice(&[FIELD_NAME, 0, 0, 0, 1, b'x']);
+
+        let expected = Message::Manifest(vec![FileInfo::new("x", 9, String::from("ab"))]);
+
+        assert_eq!(Message::from_bytes(&buf), Ok(expected));
+        assert_eq!(
+            Message::from_bytes(&[3, 0, 0, 0, 1, 0, 0]),
+            Err(MessageError::MissingField("name"))
+        );
+    }
+
+    #[test]
+    fn malformed_messages() {
+        assert_eq!(Message::from_bytes(&[]), Err(MessageError::Empty));
+        assert_eq!(
+            Message::from_bytes(&[0xff]),
+            Err(MessageError::UnknownTag(0xff))
+        );
+        assert_eq!(
+            Message::from_bytes(&[3, 0, 0, 0, 1]),
+            Err(MessageError::Truncated)
+        );
+        assert_eq!(
+            Message::from_bytes(&[8, 0]),
+            Err(MessageError::TrailingBytes(1))
+        );
+        assert_eq!(
+            Message::from_bytes(&[7, 0, 0, 0, 1, 0xff]),
+            Err(MessageError::InvalidUtf8)
+        );
+        assert_eq!(
+            Message::from_bytes(b"\x00HTTP/1.1"),
+            Err(MessageError::InvalidHello)
+        );
+    }
 }
@@ -0,0 +1,87 @@
+use std::{
+    num::NonZeroU64,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use tokio::time::sleep;
+
+/// Token bucket limiting throughput to `rate` bytes per second with bursts of up to one second.
+/// A single limiter can be shared between connections to enforce a global limit.
+pub struct RateLimiter {
+    rate: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: NonZeroU64) -> Self {
+        let rate = rate.get() as f64;
+
+        Self {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until `amount` bytes may pass. Amounts larger than the bucket put it into debt,
+    /// which is paid off by the following callers as well.
+    pub async fn acquire(&self, amount: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().expect("rate limiter lock poisoned");
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last).as_secs_f64();
+
+            bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.rate);
+            bucket.last = now;
+            bucket.tokens -= amount as f64;
+
+            match bucket.tokens < 0.0 {
+                true => Duration::from_secs_f64(-bucket.tokens / self.rate),
+                false => Duration::ZERO,
+            }
+        };
+
+        if !wait.is_zero() {
+            debug!("Rate limit reached, waiting {} ms", wait.as_millis());
+            sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn limits_throughput() {
+        let limiter = RateLimiter::new(NonZeroU64::new(10_000).unwrap());
+        let start = Instant::now();
+
+        // first second worth of bytes passes as a burst
+        limiter.acquire(10_000).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        limiter.acquire(2_500).await;
+        limiter.acquire(2_500).await;
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+
+    #[tokio::test]
+    async fn slowest_rate() {
+        // 1 B/s is the smallest limit, zero can't be expressed at all
+        let limiter = RateLimiter::new(NonZeroU64::MIN);
+        let start = Instant::now();
+
+        limiter.acquire(1).await;
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
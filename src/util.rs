@@ -1,12 +1,32 @@
-use std::{collections::HashMap, env, error::Error, fs, net::SocketAddr, path::PathBuf};
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    env,
+    ffi::{OsStr, OsString},
+    fmt, fs,
+    io::{self, BufRead, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    thread,
+};
 
 use log::{debug, info};
-use tokio::{fs::File, io::BufWriter};
+use tokio::{
+    fs::{self as async_fs, File, OpenOptions},
+    io::BufWriter,
+    sync::oneshot,
+};
 
-use crate::crypto;
+use crate::{crypto, Error};
 
 const PUBLIC_IPV4: &str = "https://ipinfo.io/ip";
 const PUBLIC_IPV6: &str = "https://ipv6.icanhazip.com";
+const PARTIAL_SUFFIX: &str = ".contego-part";
+const RESERVED_NAMES: [&str; 4] = ["CON", "PRN", "AUX", "NUL"];
+#[cfg(windows)]
+const WINDOWS_INVALID_CHARS: &[u8] = b"<>:\"|?*";
 
 #[derive(PartialEq, Eq)]
 pub enum Ip {
@@ -16,22 +36,29 @@ pub enum Ip {
 }
 
 impl Ip {
-    pub fn fetch(self, port: u16) -> Result<(SocketAddr, SocketAddr), Box<dyn Error>> {
+    pub fn fetch(self, port: u16) -> Result<(SocketAddr, SocketAddr), Error> {
         let addr = match self {
             Ip::V4 => PUBLIC_IPV4,
             Ip::V6 => PUBLIC_IPV6,
             Ip::Local => {
-                let addr_str = format!("127.0.0.1:{}", port);
-                let addr = addr_str.parse::<SocketAddr>()?;
+                let addr = SocketAddr::from(([127, 0, 0, 1], port));
                 return Ok((addr, addr));
             }
         };
 
         info!("Fetching IP information from {}", addr);
 
-        let res = format!("{}:{}", ureq::get(addr).call()?.into_string()?.trim(), port);
-        let display_addr = res.parse::<SocketAddr>()?;
-        let bind_addr = format!("0.0.0.0:{}", port).parse::<SocketAddr>()?;
+        let response = match ureq::get(addr).call() {
+            Ok(response) => response.into_string().map_err(Error::Network)?,
+            Err(e) => return Err(Error::Network(io::Error::other(e))),
+        };
+
+        let res = format!("{}:{}", response.trim(), port);
+        let display_addr = match res.parse::<SocketAddr>() {
+            Ok(addr) => addr,
+            Err(_) => return Err(Error::Config(format!("Invalid public address '{}'", res))),
+        };
+        let bind_addr = SocketAddr::from(([0, 0, 0, 0], port));
 
         debug!("IP: {}", res);
 
@@ -39,33 +66,44 @@ impl Ip {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct FileInfo {
-    pub name: String,
+    /// Relative path separated with `/`, not necessarily valid UTF-8
+    pub name: Vec<u8>,
     pub size: u64,
     pub hash: String,
 }
 
 impl FileInfo {
-    pub fn new(name: String, size: u64, hash: String) -> Self {
-        Self { name, size, hash }
+    pub fn new(name: impl Into<Vec<u8>>, size: u64, hash: String) -> Self {
+        Self {
+            name: name.into(),
+            size,
+            hash,
+        }
+    }
+
+    pub fn display_name(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.name)
     }
 }
 
 pub fn filepaths(
     source: Option<PathBuf>,
     files: Option<Vec<PathBuf>>,
-) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+) -> Result<Vec<PathBuf>, Error> {
     info!("Collecting filepaths");
 
     let mut paths = Vec::new();
 
     if let Some(source) = source {
-        let home = env::var("HOME")?;
+        let home = match env::var("HOME") {
+            Ok(home) => home,
+            Err(_) => return Err(Error::Config(String::from("HOME is not set"))),
+        };
         let content = fs::read_to_string(source)?;
         paths = content
             .lines()
-            .into_iter()
             .map(|p| PathBuf::from(p.replace('~', &home)))
             .collect();
     } else if let Some(files) = files {
@@ -79,26 +117,22 @@ pub fn filepaths(
 
 pub async fn metadata(
     files: &Vec<PathBuf>,
-) -> Result<(Vec<FileInfo>, HashMap<String, PathBuf>), Box<dyn Error>> {
+) -> Result<(Vec<FileInfo>, HashMap<String, PathBuf>), Error> {
     info!("Collecting metadata");
 
     let mut metadata = Vec::new();
     let mut index = HashMap::new();
 
-    for path in files {
-        debug!("Collecting '{}' metadata", path.to_str().unwrap());
+    for (path, name) in expand(files)? {
+        debug!("Collecting '{}' metadata", path.display());
 
-        let split = path.to_str().unwrap().split('/').collect::<Vec<&str>>();
-        let name = split[split.len() - 1].to_string();
-        let handle = File::open(path).await?;
+        let handle = File::open(&path).await?;
         let size = handle.metadata().await?.len();
-        let hash = crypto::try_hash(path)?;
+        let hash = crypto::try_hash(&path)?;
 
-        if size > 0 {
-            let info = FileInfo::new(name, size, hash.clone());
-            metadata.push(info);
-            index.insert(hash, path.clone());
-        }
+        let info = FileInfo::new(name, size, hash.clone());
+        metadata.push(info);
+        index.insert(hash, path);
     }
 
     debug!(
@@ -109,16 +143,314 @@ pub async fn metadata(
     Ok((metadata, index))
 }
 
-pub async fn new_file(
-    mut path: PathBuf,
-    name: &str,
-) -> Result<(BufWriter<File>, PathBuf), Box<dyn Error + Send + Sync>> {
-    debug!("New file handle for '{}'", name);
+type NamedPath = (PathBuf, Vec<u8>);
+
+/// Resolves the given paths into files paired with their transmitted names. Directories are
+/// walked recursively and their files named relative to the directory's parent (e.g. `dir/sub/file`).
+fn expand(paths: &Vec<PathBuf>) -> Result<Vec<NamedPath>, Error> {
+    let mut files = Vec::new();
+
+    for path in paths {
+        // `.` and `..` have no name of their own, so take the one of the directory they point to
+        let name = match path.file_name() {
+            Some(name) => os_to_bytes(name),
+            None => match path.canonicalize()?.file_name() {
+                Some(name) => os_to_bytes(name),
+                None => return Err(Error::Config(format!("Invalid path '{}'", path.display()))),
+            },
+        };
+
+        if !path.is_dir() {
+            files.push((path.clone(), name));
+            continue;
+        }
+
+        let mut found = Vec::new();
+        walk(path, &mut found)?;
+
+        for file in found {
+            let relative = file
+                .strip_prefix(path)
+                .expect("walked files are inside the directory");
+            let mut parts = vec![name.clone()];
+            parts.extend(relative.components().map(|c| os_to_bytes(c.as_os_str())));
+
+            files.push((file, parts.join(&b'/')));
+        }
+    }
+
+    Ok(files)
+}
+
+pub fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), io::Error> {
+    debug!("Walking directory '{}'", dir.display());
+
+    let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        let filetype = entry.file_type()?;
+
+        // symlinked directories are skipped to avoid cycles
+        if filetype.is_dir() {
+            walk(&path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NameError {
+    Empty,
+    Absolute(String),
+    Traversal(String),
+    Reserved(String),
+    InvalidComponent(String),
+    InvalidEncoding(String),
+}
+
+impl fmt::Display for NameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NameError::Empty => write!(f, "Refusing an empty file name"),
+            NameError::Absolute(name) => write!(f, "Refusing absolute file name '{}'", name),
+            NameError::Traversal(name) => {
+                write!(
+                    f,
+                    "Refusing file name '{}' escaping the output folder",
+                    name
+                )
+            }
+            NameError::Reserved(name) => write!(f, "Refusing reserved file name '{}'", name),
+            NameError::InvalidComponent(name) => {
+                write!(f, "Refusing file name '{}' with an invalid component", name)
+            }
+            NameError::InvalidEncoding(name) => {
+                write!(
+                    f,
+                    "File name '{}' can't be represented on this platform",
+                    name
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for NameError {}
+
+/// Validates a file name received from the server, returning it as a relative path
+/// guaranteed to stay inside the output folder.
+pub fn sanitize_name(name: &[u8]) -> Result<PathBuf, NameError> {
+    let display = || String::from_utf8_lossy(name).to_string();
+
+    if name.is_empty() {
+        return Err(NameError::Empty);
+    }
+
+    // both separators are treated equally regardless of the platform
+    let is_separator = |b: &u8| *b == b'/' || *b == b'\\';
+    let is_drive = name.len() >= 2 && name[1] == b':' && name[0].is_ascii_alphabetic();
+
+    if is_separator(&name[0]) || is_drive {
+        return Err(NameError::Absolute(display()));
+    }
+
+    let mut path = PathBuf::new();
+
+    for component in name.split(is_separator) {
+        match component {
+            b".." => return Err(NameError::Traversal(display())),
+            b"" | b"." => return Err(NameError::InvalidComponent(display())),
+            c if c.iter().any(|b| b.is_ascii_control()) || !is_valid_on_platform(c) => {
+                return Err(NameError::InvalidComponent(display()))
+            }
+            c if is_reserved(c) => return Err(NameError::Reserved(display())),
+            c => match os_from_bytes(c) {
+                Some(c) => path.push(c),
+                None => return Err(NameError::InvalidEncoding(display())),
+            },
+        }
+    }
+
+    Ok(path)
+}
+
+fn is_reserved(component: &[u8]) -> bool {
+    // device names are reserved on Windows even with an extension (e.g. `CON.txt`)
+    let stem = component.split(|b| *b == b'.').next().unwrap_or_default();
+    let stem = String::from_utf8_lossy(stem)
+        .trim_end()
+        .to_ascii_uppercase();
+
+    if RESERVED_NAMES.contains(&stem.as_str()) {
+        return true;
+    }
+
+    match stem
+        .strip_prefix("COM")
+        .or_else(|| stem.strip_prefix("LPT"))
+    {
+        Some(n) => n.len() == 1 && matches!(n.as_bytes()[0], b'1'..=b'9'),
+        None => false,
+    }
+}
+
+/// NTFS refuses these characters or trailing dots and spaces, and reads `file:stream` as an
+/// alternate data stream of an existing file.
+#[cfg(windows)]
+fn is_valid_on_platform(component: &[u8]) -> bool {
+    !component.iter().any(|b| WINDOWS_INVALID_CHARS.contains(b))
+        && !matches!(component.last(), Some(b'.' | b' '))
+}
+
+#[cfg(not(windows))]
+fn is_valid_on_platform(_component: &[u8]) -> bool {
+    true
+}
+
+#[cfg(unix)]
+fn os_to_bytes(s: &OsStr) -> Vec<u8> {
+    s.as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn os_to_bytes(s: &OsStr) -> Vec<u8> {
+    s.to_string_lossy().as_bytes().to_vec()
+}
+
+#[cfg(unix)]
+fn os_from_bytes(bytes: &[u8]) -> Option<OsString> {
+    Some(OsStr::from_bytes(bytes).to_os_string())
+}
+
+#[cfg(not(unix))]
+fn os_from_bytes(bytes: &[u8]) -> Option<OsString> {
+    std::str::from_utf8(bytes).ok().map(OsString::from)
+}
+
+/// Resolves the final and the temporary download path of a received file. Downloads are written
+/// into a hidden file next to the final one and only renamed into place after verification.
+pub fn download_paths(mut path: PathBuf, name: &[u8]) -> Result<(PathBuf, PathBuf), NameError> {
+    path.push(sanitize_name(name)?);
+
+    let mut partial_name = OsString::from(".");
+    partial_name.push(path.file_name().unwrap_or_default());
+    partial_name.push(PARTIAL_SUFFIX);
 
-    path.push(name);
-    let handle = File::create(&path).await?;
+    let partial = path.with_file_name(partial_name);
 
-    Ok((BufWriter::new(handle), path))
+    Ok((path, partial))
+}
+
+/// Finds the first free variant of a path by appending a counter to the file stem (`file (1).txt`).
+pub fn unique_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default();
+    let ext = path.extension();
+
+    (1..)
+        .map(|n| {
+            let mut name = stem.to_os_string();
+            name.push(format!(" ({})", n));
+
+            if let Some(ext) = ext {
+                name.push(".");
+                name.push(ext);
+            }
+
+            path.with_file_name(name)
+        })
+        .find(|p| !p.exists())
+        .expect("unbounded range always yields a free path")
+}
+
+pub async fn new_file(path: &Path) -> Result<BufWriter<File>, Error> {
+    debug!("New file handle for '{}'", path.display());
+
+    if let Some(parent) = path.parent() {
+        async_fs::create_dir_all(parent).await?;
+    }
+
+    let handle = File::create(path).await?;
+
+    Ok(BufWriter::new(handle))
+}
+
+/// Reopens a previously interrupted download in append mode, returning the amount of bytes already on disk.
+pub async fn resume_file(path: &Path, size: u64) -> Result<Option<(BufWriter<File>, u64)>, Error> {
+    let offset = match async_fs::metadata(path).await {
+        Ok(meta) if meta.is_file() => meta.len(),
+        _ => return Ok(None),
+    };
+
+    if offset == 0 || offset >= size {
+        return Ok(None);
+    }
+
+    debug!("Resuming '{}' from offset {}", path.display(), offset);
+
+    let handle = OpenOptions::new().append(true).open(path).await?;
+
+    Ok(Some((BufWriter::new(handle), offset)))
+}
+
+/// Runs a blocking terminal interaction on its own thread. Unlike `spawn_blocking`, a thread
+/// stuck reading stdin doesn't keep the runtime from shutting down, e.g. after Ctrl+C.
+pub async fn interact<T, F>(f: F) -> Result<T, Error>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+
+    thread::spawn(move || {
+        // the receiver is gone if the session ended in the meantime
+        let _ = tx.send(f());
+    });
+
+    match rx.await {
+        Ok(result) => result,
+        Err(_) => Err(Error::Io(io::Error::other("Terminal prompt failed"))),
+    }
+}
+
+/// Asks on the terminal whether the verification code matches the one shown on the peer's side.
+pub fn confirm_code(peer: &SocketAddr, code: &str) -> Result<bool, Error> {
+    let mut stdout = io::stdout();
+
+    write!(
+        stdout,
+        "Verification code for {} is {}, does it match the peer's? [y/N]: ",
+        peer, code
+    )?;
+    stdout.flush()?;
+
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+
+    Ok(matches!(line.trim(), "y" | "Y" | "yes"))
+}
+
+/// Formats a byte amount with binary units (e.g. `1.5 MiB`).
+pub fn human_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+
+    let mut size = size as f64 / 1024.0;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
 }
 
 pub fn ascii() {
@@ -130,3 +462,147 @@ pub fn ascii() {
                          /____/        ";
     println!("{}\n", ascii);
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn unique_paths() {
+        let dir = PathBuf::from("./tests/output/unique");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("file.txt"), "").unwrap();
+        fs::write(dir.join("file (1).txt"), "").unwrap();
+
+        assert_eq!(unique_path(&dir.join("file.txt")), dir.join("file (2).txt"));
+        assert_eq!(unique_path(&dir.join("noext")), dir.join("noext (1)"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unnamed_paths() {
+        let dir = PathBuf::from("./tests/output/unnamed");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/file.txt"), "").unwrap();
+
+        // `..` has no file name, the shared directory is named after what it resolves to
+        let files = expand(&vec![dir.join("sub/..")]).unwrap();
+        let names = files.into_iter().map(|(_, name)| name).collect::<Vec<_>>();
+
+        assert_eq!(names, [b"unnamed/sub/file.txt".to_vec()]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human_size(1023), "1023 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+
+    #[test]
+    fn valid_names() {
+        assert_eq!(sanitize_name(b"a.txt").unwrap(), PathBuf::from("a.txt"));
+        assert_eq!(
+            sanitize_name(b"dir/sub/a.txt").unwrap(),
+            ["dir", "sub", "a.txt"].iter().collect::<PathBuf>()
+        );
+        assert_eq!(sanitize_name(b"..a").unwrap(), PathBuf::from("..a"));
+        assert_eq!(
+            sanitize_name(b"console.log").unwrap(),
+            PathBuf::from("console.log")
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn unix_names() {
+        assert_eq!(
+            sanitize_name(b"notes:v2.txt").unwrap(),
+            PathBuf::from("notes:v2.txt")
+        );
+        assert_eq!(
+            sanitize_name(b"trailing.").unwrap(),
+            PathBuf::from("trailing.")
+        );
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn windows_names() {
+        for name in [
+            "victim.txt:evil",
+            "a<b",
+            "dir/what?",
+            "star*",
+            "pipe|",
+            "quote\"",
+            "dot.",
+            "space ",
+        ] {
+            let expected = Err(NameError::InvalidComponent(name.to_string()));
+            assert_eq!(
+                sanitize_name(name.as_bytes()),
+                expected,
+                "Accepted '{}'",
+                name
+            );
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn non_utf8_names() {
+        let name = b"dir/\xff\xfe.bin";
+        let expected = PathBuf::from("dir").join(OsStr::from_bytes(b"\xff\xfe.bin"));
+
+        assert_eq!(sanitize_name(name).unwrap(), expected);
+    }
+
+    #[test]
+    fn hostile_names() {
+        assert_eq!(sanitize_name(b""), Err(NameError::Empty));
+
+        for name in ["/etc/passwd", "\\\\srv\\share", "C:\\evil.exe"] {
+            let expected = Err(NameError::Absolute(name.to_string()));
+            assert_eq!(
+                sanitize_name(name.as_bytes()),
+                expected,
+                "Accepted '{}'",
+                name
+            );
+        }
+
+        for name in ["../.bashrc", "a/../../b", "a\\..\\..\\b", ".."] {
+            let expected = Err(NameError::Traversal(name.to_string()));
+            assert_eq!(
+                sanitize_name(name.as_bytes()),
+                expected,
+                "Accepted '{}'",
+                name
+            );
+        }
+
+        for name in ["a//b", "./a", "dir/", "a\0b", "a\nb"] {
+            let expected = Err(NameError::InvalidComponent(name.to_string()));
+            assert_eq!(
+                sanitize_name(name.as_bytes()),
+                expected,
+                "Accepted '{}'",
+                name
+            );
+        }
+
+        for name in ["CON", "dir/nul.txt", "LPT1", "com9.tar.gz"] {
+            let expected = Err(NameError::Reserved(name.to_string()));
+            assert_eq!(
+                sanitize_name(name.as_bytes()),
+                expected,
+                "Accepted '{}'",
+                name
+            );
+        }
+    }
+}
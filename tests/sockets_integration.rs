@@ -1,59 +1,62 @@
 use std::{
     fs::{self, File},
     io::{BufWriter, Write},
+    net::SocketAddr,
     path::PathBuf,
     str::FromStr,
+    sync::{Arc, Mutex},
 };
 
 use contego::{
-    client::Client,
-    server::Server,
-    util::{metadata, Ip},
+    client::{Client, ConflictPolicy},
+    crypto::{CipherSuite, Crypto, RekeyLimit},
+    handshake::{self, Capabilities},
+    observer::{Event, Observer},
+    server::{LimitPolicy, Server, ServerBuilder},
+    sockets::{Message, SocketHandler},
+    util::{metadata, FileInfo, Ip, NameError},
+    Error,
 };
 use env_logger::Env;
 use log::debug;
 use ntest::timeout;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use tokio::{fs::read_to_string, sync::mpsc};
+use tokio::{
+    fs::read_to_string,
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+    time::{sleep, Duration},
+};
 
 #[tokio::test]
 #[timeout(2000)]
 /// Ensures backend communications integrity & the ability to handle individual requests.
 async fn sockets_integration() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("debug"))
-        .is_test(true)
-        .try_init()
-        .unwrap();
+    init_logger();
 
     debug!("Initializing and starting the test");
 
-    let (testdata, paths) = testdata();
+    let (testdata, paths) = testdata(&["1.txt", "2.txt", "3.txt"]);
     let (metadata, index) = metadata(&paths).await.unwrap();
 
     let (display_addr, bind_addr) = Ip::Local.fetch(8080).unwrap();
     let outdir = PathBuf::from("./tests/output/");
-    let key = String::from("testkey");
-    let c_key = key.clone();
 
-    let (tx, rx) = mpsc::channel::<()>(1);
+    let builder = Server::builder(display_addr, "testkey")
+        .share(metadata, index)
+        .max_clients(1);
+    let (kill, server) = serve(&bind_addr, builder).await;
 
-    let server_handle = tokio::spawn(async move {
-        debug!("Initializing the asynchronous server task");
-        let server = Server::new(display_addr, key, 8192, metadata, index);
-        debug!("Starting to listen to incoming connections");
-        server.start(rx, &bind_addr).await.unwrap();
-    });
+    debug!("Connecting to the server");
+    let client = Client::builder(display_addr, "testkey")
+        .output(outdir)
+        .build();
+    client.connection().await.unwrap();
 
-    let client_handle = tokio::spawn(async move {
-        debug!("Initializing the asynchronous client task");
-        let client = Client::new(display_addr, c_key, outdir);
-        debug!("Connecting to the server");
-        client.connection().await.unwrap();
-    });
-
-    client_handle.await.unwrap();
-    tx.send(()).await.unwrap();
-    server_handle.await.unwrap();
+    kill.send(()).await.unwrap();
+    server.await.unwrap();
 
     debug!("Checking for file integrity");
 
@@ -74,13 +77,759 @@ async fn sockets_integration() {
     }
 }
 
-fn testdata() -> (Vec<(&'static str, String)>, Vec<PathBuf>) {
-    let mut paths = Vec::new();
-    let testdata = vec![
-        ("1.txt", generate_data()),
-        ("2.txt", generate_data()),
-        ("3.txt", generate_data()),
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures a partially downloaded file is continued from its current length instead of restarted.
+async fn resume_integration() {
+    init_logger();
+
+    let (testdata, paths) = testdata(&["resume.txt"]);
+    let (metadata, index) = metadata(&paths).await.unwrap();
+
+    let (content, partial) = (&testdata[0].1, &testdata[0].1[..12]);
+    fs::write("./tests/output/.resume.txt.contego-part", partial).unwrap();
+
+    let (display_addr, bind_addr) = Ip::Local.fetch(8081).unwrap();
+    let outdir = PathBuf::from("./tests/output/");
+
+    let builder = Server::builder(display_addr, "testkey")
+        .share(metadata, index)
+        .max_clients(1);
+    let (kill, server) = serve(&bind_addr, builder).await;
+
+    let client = Client::builder(display_addr, "testkey")
+        .output(outdir)
+        .build();
+    client.connection().await.unwrap();
+
+    kill.send(()).await.unwrap();
+    server.await.unwrap();
+
+    let recv_content = read_to_string("./tests/output/resume.txt").await.unwrap();
+    assert_eq!(
+        &recv_content, content,
+        "Resumed output doesn't match the input"
+    );
+
+    fs::remove_file("./tests/output/resume.txt").unwrap();
+    fs::remove_file("./tests/data/resume.txt").unwrap();
+}
+
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures a partial file left over from an older version is discarded and downloaded again.
+async fn stale_resume_integration() {
+    init_logger();
+
+    let (testdata, paths) = testdata(&["stale.txt"]);
+    let (metadata, index) = metadata(&paths).await.unwrap();
+
+    fs::write("./tests/output/.stale.txt.contego-part", "outdated").unwrap();
+
+    let (display_addr, bind_addr) = Ip::Local.fetch(8097).unwrap();
+    let outdir = PathBuf::from("./tests/output/");
+
+    let builder = Server::builder(display_addr, "testkey")
+        .share(metadata, index)
+        .max_clients(1);
+    let (kill, server) = serve(&bind_addr, builder).await;
+
+    let client = Client::builder(display_addr, "testkey")
+        .output(outdir)
+        .build();
+    client.connection().await.unwrap();
+
+    kill.send(()).await.unwrap();
+    server.await.unwrap();
+
+    let recv_content = read_to_string("./tests/output/stale.txt").await.unwrap();
+    assert_eq!(recv_content, testdata[0].1);
+    assert!(!PathBuf::from("./tests/output/.stale.txt.contego-part").exists());
+
+    fs::remove_file("./tests/output/stale.txt").unwrap();
+    fs::remove_file("./tests/data/stale.txt").unwrap();
+}
+
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures an idle session doesn't block other clients from being served.
+async fn concurrent_integration() {
+    init_logger();
+
+    let (testdata, paths) = testdata(&["concurrent.txt"]);
+    let (metadata, index) = metadata(&paths).await.unwrap();
+
+    let (display_addr, bind_addr) = Ip::Local.fetch(8082).unwrap();
+    let outdir = PathBuf::from("./tests/output/");
+
+    let builder = Server::builder(display_addr, "testkey")
+        .share(metadata, index)
+        .max_clients(2);
+    let (kill, server) = serve(&bind_addr, builder).await;
+
+    // occupies the first slot without ever sending a hello
+    let _idle = TcpStream::connect(display_addr).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let client = Client::builder(display_addr, "testkey")
+        .output(outdir)
+        .build();
+    client.connection().await.unwrap();
+
+    kill.send(()).await.unwrap();
+    server.await.unwrap();
+
+    let recv_content = read_to_string("./tests/output/concurrent.txt")
+        .await
+        .unwrap();
+    assert_eq!(recv_content, testdata[0].1);
+
+    fs::remove_file("./tests/output/concurrent.txt").unwrap();
+    fs::remove_file("./tests/data/concurrent.txt").unwrap();
+}
+
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures connections exceeding the limit are rejected with an error under the reject policy.
+async fn connection_limit_integration() {
+    init_logger();
+
+    let (display_addr, bind_addr) = Ip::Local.fetch(8083).unwrap();
+    let outdir = PathBuf::from("./tests/output/");
+
+    let builder = Server::builder(display_addr, "testkey")
+        .max_clients(1)
+        .limit_policy(LimitPolicy::Reject);
+    let (kill, server) = serve(&bind_addr, builder).await;
+
+    let _idle = TcpStream::connect(display_addr).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let client = Client::builder(display_addr, "testkey")
+        .output(outdir)
+        .build();
+    let err = client.connection().await.unwrap_err();
+    assert!(
+        matches!(err, Error::Peer(ref reason) if reason.contains("busy")),
+        "Unexpected error: {}",
+        err
+    );
+
+    kill.send(()).await.unwrap();
+    server.await.unwrap();
+}
+
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures shared directories are recreated with their relative structure on the client.
+async fn directory_integration() {
+    init_logger();
+
+    let root = PathBuf::from("./tests/data/tree");
+    fs::create_dir_all(root.join("sub/deeper")).unwrap();
+    fs::write(root.join("a.txt"), generate_data()).unwrap();
+    fs::write(root.join("sub/b.txt"), generate_data()).unwrap();
+    fs::write(root.join("sub/deeper/c.txt"), generate_data()).unwrap();
+    fs::write(root.join("sub/empty"), "").unwrap();
+
+    let (metadata, index) = metadata(&vec![root.clone()]).await.unwrap();
+
+    let names = metadata
+        .iter()
+        .map(|f| f.display_name())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        [
+            "tree/a.txt",
+            "tree/sub/b.txt",
+            "tree/sub/deeper/c.txt",
+            "tree/sub/empty"
+        ]
+    );
+
+    let (display_addr, bind_addr) = Ip::Local.fetch(8084).unwrap();
+    let outdir = PathBuf::from("./tests/output/");
+
+    let builder = Server::builder(display_addr, "testkey")
+        .share(metadata, index)
+        .max_clients(1);
+    let (kill, server) = serve(&bind_addr, builder).await;
+
+    let client = Client::builder(display_addr, "testkey")
+        .output(outdir)
+        .build();
+    client.connection().await.unwrap();
+
+    kill.send(()).await.unwrap();
+    server.await.unwrap();
+
+    for name in ["a.txt", "sub/b.txt", "sub/deeper/c.txt", "sub/empty"] {
+        let sent = fs::read_to_string(root.join(name)).unwrap();
+        let recv = fs::read_to_string(PathBuf::from("./tests/output/tree").join(name)).unwrap();
+        assert_eq!(recv, sent, "Output of '{}' doesn't match the input", name);
+    }
+
+    fs::remove_dir_all("./tests/output/tree").unwrap();
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures the client refuses a manifest trying to write outside of the output folder.
+async fn hostile_manifest_integration() {
+    init_logger();
+
+    let key = "testkey";
+    let listener = TcpListener::bind("127.0.0.1:8085").await.unwrap();
+
+    let server_handle = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut handler = SocketHandler::new(&mut socket);
+
+        let session = handshake::hello(&mut handler, Capabilities::default(), false)
+            .await
+            .unwrap();
+        let crypto = Crypto::new(&mut handler, key, false, &session)
+            .await
+            .unwrap();
+        handler.recv_message().await.unwrap();
+        handler
+            .send_message(&Message::AuthResult(Some(crypto.confirmation(false))))
+            .await
+            .unwrap();
+        handler.set_crypto(crypto);
+
+        let manifest = vec![
+            FileInfo::new("fine.txt", 4, String::from("abcd")),
+            FileInfo::new("../escaped.txt", 4, String::from("ef01")),
+        ];
+        handler
+            .send_message(&Message::Manifest(manifest))
+            .await
+            .unwrap();
+    });
+
+    let addr = "127.0.0.1:8085".parse().unwrap();
+    let client = Client::builder(addr, key.to_string())
+        .output(PathBuf::from("./tests/output/"))
+        .build();
+    let err = client.connection().await.unwrap_err();
+
+    assert!(
+        matches!(err, Error::Name(NameError::Traversal(ref name)) if name == "../escaped.txt"),
+        "Unexpected error: {}",
+        err
+    );
+    assert!(!PathBuf::from("./tests/output/fine.txt").exists());
+    assert!(!PathBuf::from("./tests/escaped.txt").exists());
+
+    server_handle.await.unwrap();
+}
+
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures a hello altered in transit fails the key confirmation instead of going unnoticed.
+async fn tampered_hello_integration() {
+    init_logger();
+
+    let key = "testkey";
+    let listener = TcpListener::bind("127.0.0.1:8095").await.unwrap();
+
+    let server_handle = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut handler = SocketHandler::new(&mut socket);
+
+        let mut session = handshake::hello(&mut handler, Capabilities::default(), false)
+            .await
+            .unwrap();
+        // the server sees a different first cipher than the client offered, as if rewritten in transit
+        session.transcript[10] = CipherSuite::ChaCha20Poly1305.id();
+
+        let crypto = Crypto::new(&mut handler, key, false, &session)
+            .await
+            .unwrap();
+        let tag = match handler.recv_message().await.unwrap() {
+            Message::Auth(tag) => tag,
+            other => panic!("Unexpected {} message", other.name()),
+        };
+        assert!(!crypto.verify_confirmation(true, &tag));
+
+        handler
+            .send_message(&Message::AuthResult(None))
+            .await
+            .unwrap();
+    });
+
+    let addr = "127.0.0.1:8095".parse().unwrap();
+    let client = Client::builder(addr, key).build();
+    let err = client.connection().await.unwrap_err();
+
+    server_handle.await.unwrap();
+
+    assert!(matches!(err, Error::Auth), "Unexpected error: {}", err);
+}
+
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures listing returns the manifest without downloading anything.
+async fn list_integration() {
+    init_logger();
+
+    let (testdata, paths) = testdata(&["listed.txt"]);
+    let (metadata, index) = metadata(&paths).await.unwrap();
+    let expected = metadata.clone();
+
+    let (display_addr, bind_addr) = Ip::Local.fetch(8086).unwrap();
+    let outdir = PathBuf::from("./tests/output/");
+
+    let builder = Server::builder(display_addr, "testkey")
+        .share(metadata, index)
+        .max_clients(1);
+    let (kill, server) = serve(&bind_addr, builder).await;
+
+    let client = Client::builder(display_addr, "testkey")
+        .output(outdir)
+        .build();
+    let listing = client.list().await.unwrap();
+
+    kill.send(()).await.unwrap();
+    server.await.unwrap();
+
+    assert_eq!(listing, expected);
+    assert_eq!(listing[0].size, testdata[0].1.len() as u64);
+    assert!(!PathBuf::from("./tests/output/listed.txt").exists());
+
+    fs::remove_file("./tests/data/listed.txt").unwrap();
+}
+
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures existing files are kept and new ones stored under a free name with the rename policy.
+async fn conflict_integration() {
+    init_logger();
+
+    let (testdata, paths) = testdata(&["conflict.txt"]);
+    let (metadata, index) = metadata(&paths).await.unwrap();
+    fs::write("./tests/output/conflict.txt", "existing").unwrap();
+
+    let (display_addr, bind_addr) = Ip::Local.fetch(8087).unwrap();
+    let outdir = PathBuf::from("./tests/output/");
+
+    let builder = Server::builder(display_addr, "testkey")
+        .share(metadata, index)
+        .max_clients(1);
+    let (kill, server) = serve(&bind_addr, builder).await;
+
+    let client = Client::builder(display_addr, "testkey")
+        .output(outdir)
+        .on_conflict(ConflictPolicy::Rename)
+        .build();
+    client.connection().await.unwrap();
+
+    kill.send(()).await.unwrap();
+    server.await.unwrap();
+
+    let existing = read_to_string("./tests/output/conflict.txt").await.unwrap();
+    let renamed = read_to_string("./tests/output/conflict (1).txt")
+        .await
+        .unwrap();
+    assert_eq!(existing, "existing");
+    assert_eq!(renamed, testdata[0].1);
+
+    fs::remove_file("./tests/output/conflict.txt").unwrap();
+    fs::remove_file("./tests/output/conflict (1).txt").unwrap();
+    fs::remove_file("./tests/data/conflict.txt").unwrap();
+}
+
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures mirroring only fetches missing or changed files and removes stale local ones.
+async fn sync_integration() {
+    init_logger();
+
+    let (testdata, mut paths) = testdata(&["sync-same.txt", "sync-changed.txt", "sync-new.txt"]);
+    let empty = PathBuf::from("./tests/data/sync-empty.txt");
+    fs::write(&empty, "").unwrap();
+    paths.push(empty.clone());
+    let (metadata, index) = metadata(&paths).await.unwrap();
+
+    let outdir = PathBuf::from("./tests/output/sync");
+    fs::create_dir_all(outdir.join("stale")).unwrap();
+    fs::write(outdir.join("sync-empty.txt"), "").unwrap();
+    fs::write(outdir.join("sync-same.txt"), &testdata[0].1).unwrap();
+    fs::write(outdir.join("sync-changed.txt"), "outdated").unwrap();
+    fs::write(outdir.join("stale/old.txt"), "old").unwrap();
+
+    let unchanged = fs::metadata(outdir.join("sync-same.txt"))
+        .unwrap()
+        .modified()
+        .unwrap();
+
+    let (display_addr, bind_addr) = Ip::Local.fetch(8088).unwrap();
+
+    let builder = Server::builder(display_addr, "testkey")
+        .share(metadata, index)
+        .max_clients(1);
+    let (kill, server) = serve(&bind_addr, builder).await;
+
+    let client = Client::builder(display_addr, "testkey")
+        .output(outdir.clone())
+        .on_conflict(ConflictPolicy::SkipIfHashMatches)
+        .prune(true)
+        .build();
+    client.connection().await.unwrap();
+
+    kill.send(()).await.unwrap();
+    server.await.unwrap();
+
+    for (name, content) in &testdata {
+        let recv_content = read_to_string(outdir.join(name)).await.unwrap();
+        assert_eq!(&recv_content, content, "Output '{}' isn't in sync", name);
+        fs::remove_file(PathBuf::from("./tests/data/").join(name)).unwrap();
+    }
+
+    let modified = fs::metadata(outdir.join("sync-same.txt"))
+        .unwrap()
+        .modified()
+        .unwrap();
+    assert_eq!(modified, unchanged, "Unchanged file was downloaded again");
+    assert!(!outdir.join("stale").exists());
+    assert!(
+        outdir.join("sync-empty.txt").is_file(),
+        "Shared empty file was pruned"
+    );
+
+    fs::remove_file(empty).unwrap();
+
+    fs::remove_dir_all(outdir).unwrap();
+}
+
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures client and server report the lifecycle of a transfer to their observers.
+async fn observer_integration() {
+    init_logger();
+
+    let (testdata, paths) = testdata(&["observed.txt"]);
+    let (metadata, index) = metadata(&paths).await.unwrap();
+
+    let (display_addr, bind_addr) = Ip::Local.fetch(8089).unwrap();
+    let outdir = PathBuf::from("./tests/output/");
+
+    let (server_observer, server_events) = recorder();
+    let (client_observer, client_events) = recorder();
+
+    let builder = Server::builder(display_addr, "testkey")
+        .share(metadata, index)
+        .max_clients(1)
+        .observer(server_observer);
+    let (kill, server) = serve(&bind_addr, builder).await;
+
+    let client = Client::builder(display_addr, "testkey")
+        .output(outdir.clone())
+        .observer(client_observer)
+        .build();
+    client.connection().await.unwrap();
+
+    // the server finishes its side of the connection after the client is done
+    sleep(Duration::from_millis(100)).await;
+
+    kill.send(()).await.unwrap();
+    server.await.unwrap();
+
+    let expected = [
+        "Connected",
+        "Authorized",
+        "Manifest",
+        "Chunk",
+        "Verified",
+        "Disconnected",
     ];
+    assert_eq!(*client_events.lock().unwrap(), expected);
+    assert_eq!(*server_events.lock().unwrap(), expected);
+
+    for (name, _) in &testdata {
+        fs::remove_file(outdir.join(name)).unwrap();
+        fs::remove_file(PathBuf::from("./tests/data/").join(name)).unwrap();
+    }
+}
+
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures a wrong access key fails the connection instead of ending it silently.
+async fn auth_failure_integration() {
+    init_logger();
+
+    let (_, paths) = testdata(&["unauthorized.txt"]);
+    let (metadata, index) = metadata(&paths).await.unwrap();
+
+    let (display_addr, bind_addr) = Ip::Local.fetch(8090).unwrap();
+    let outdir = PathBuf::from("./tests/output/");
+
+    // reports whether the server saw the session end with an authorization failure
+    let (tx, mut disconnected) = mpsc::unbounded_channel();
+    let observer = move |event: &Event<'_>| {
+        if let Event::Disconnected { error, .. } = event {
+            tx.send(matches!(error, Some(Error::Auth))).unwrap();
+        }
+    };
+
+    let builder = Server::builder(display_addr, "testkey")
+        .share(metadata, index)
+        .max_clients(1)
+        .observer(observer);
+    let (kill, server) = serve(&bind_addr, builder).await;
+
+    let client = Client::builder(display_addr, String::from("wrongkey"))
+        .output(outdir)
+        .build();
+    let err = client.connection().await.unwrap_err();
+    let server_auth_failed = disconnected.recv().await.unwrap();
+
+    kill.send(()).await.unwrap();
+    server.await.unwrap();
+
+    assert!(matches!(err, Error::Auth), "Unexpected error: {}", err);
+    assert!(
+        server_auth_failed,
+        "Server didn't report the failed authorization"
+    );
+    assert!(!PathBuf::from("./tests/output/unauthorized.txt").exists());
+
+    fs::remove_file("./tests/data/unauthorized.txt").unwrap();
+}
+
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures a verification code rejected by the host fails the client with a verification error.
+async fn code_rejected_integration() {
+    init_logger();
+
+    let key = "testkey";
+    let listener = TcpListener::bind("127.0.0.1:8096").await.unwrap();
+
+    let server_handle = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut handler = SocketHandler::new(&mut socket);
+
+        let session = handshake::hello(&mut handler, Capabilities::default(), false)
+            .await
+            .unwrap();
+        Crypto::new(&mut handler, key, false, &session)
+            .await
+            .unwrap();
+        handler.recv_message().await.unwrap();
+
+        // what the host sends when the code is denied at its prompt
+        handler.send_message(&Message::CodeRejected).await.unwrap();
+    });
+
+    let addr = "127.0.0.1:8096".parse().unwrap();
+    let client = Client::builder(addr, key).build();
+    let err = client.connection().await.unwrap_err();
+
+    server_handle.await.unwrap();
+
+    assert!(
+        matches!(err, Error::Verification),
+        "Unexpected error: {}",
+        err
+    );
+}
+
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures a lost connection is reported as a network failure rather than a local I/O error.
+async fn network_failure_integration() {
+    init_logger();
+
+    let listener = TcpListener::bind("127.0.0.1:8094").await.unwrap();
+
+    // hangs up before the hello exchange
+    let server_handle = tokio::spawn(async move {
+        let (socket, _) = listener.accept().await.unwrap();
+        drop(socket);
+    });
+
+    let addr = "127.0.0.1:8094".parse().unwrap();
+    let client = Client::builder(addr, "testkey").build();
+    let err = client.connection().await.unwrap_err();
+
+    server_handle.await.unwrap();
+
+    assert!(
+        matches!(err, Error::Network(_)),
+        "Unexpected error: {}",
+        err
+    );
+}
+
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures the server drops clients that stay silent for longer than the configured timeout.
+async fn timeout_integration() {
+    init_logger();
+
+    let (display_addr, bind_addr) = Ip::Local.fetch(8091).unwrap();
+
+    let builder =
+        Server::builder(display_addr, "testkey").timeout(Some(Duration::from_millis(200)));
+    let (kill, server) = serve(&bind_addr, builder).await;
+
+    // never sends a hello, the server gives up waiting for it
+    let mut idle = TcpStream::connect(display_addr).await.unwrap();
+    let mut buf = [0u8; 1];
+    let read = idle.read(&mut buf).await.unwrap();
+
+    kill.send(()).await.unwrap();
+    server.await.unwrap();
+
+    assert_eq!(read, 0, "Connection wasn't closed by the server");
+}
+
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures a connection that never authenticates gives up its slot to a queued client.
+async fn handshake_timeout_integration() {
+    init_logger();
+
+    let (display_addr, bind_addr) = Ip::Local.fetch(8098).unwrap();
+
+    let builder = Server::builder(display_addr, "testkey")
+        .max_clients(1)
+        .handshake_timeout(Duration::from_millis(200));
+    let (kill, server) = serve(&bind_addr, builder).await;
+
+    // takes the only slot without ever sending a hello
+    let _idle = TcpStream::connect(display_addr).await.unwrap();
+    sleep(Duration::from_millis(100)).await;
+
+    let client = Client::builder(display_addr, "testkey").build();
+    let listing = client.list().await.unwrap();
+
+    kill.send(()).await.unwrap();
+    server.await.unwrap();
+
+    assert!(listing.is_empty());
+}
+
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures a client restricted to ChaCha20-Poly1305 still gets served by a server preferring AES.
+async fn cipher_integration() {
+    init_logger();
+
+    let (testdata, paths) = testdata(&["chacha.txt"]);
+    let (metadata, index) = metadata(&paths).await.unwrap();
+
+    let (display_addr, bind_addr) = Ip::Local.fetch(8092).unwrap();
+    let outdir = PathBuf::from("./tests/output/");
+
+    let builder = Server::builder(display_addr, "testkey")
+        .share(metadata, index)
+        .ciphers(vec![CipherSuite::Aes256Gcm, CipherSuite::ChaCha20Poly1305]);
+    let (kill, server) = serve(&bind_addr, builder).await;
+
+    let client = Client::builder(display_addr, "testkey")
+        .output(outdir)
+        .ciphers(vec![CipherSuite::ChaCha20Poly1305])
+        .build();
+    client.connection().await.unwrap();
+
+    kill.send(()).await.unwrap();
+    server.await.unwrap();
+
+    let recv_content = read_to_string("./tests/output/chacha.txt").await.unwrap();
+    assert_eq!(recv_content, testdata[0].1);
+
+    fs::remove_file("./tests/output/chacha.txt").unwrap();
+    fs::remove_file("./tests/data/chacha.txt").unwrap();
+}
+
+#[tokio::test]
+#[timeout(2000)]
+/// Ensures frequent rekeying in both directions doesn't interrupt the file stream.
+async fn rekey_integration() {
+    init_logger();
+
+    let (testdata, paths) = testdata(&["rekeyed.txt"]);
+    let (metadata, index) = metadata(&paths).await.unwrap();
+
+    let (display_addr, bind_addr) = Ip::Local.fetch(8093).unwrap();
+    let outdir = PathBuf::from("./tests/output/");
+    // switches keys after every frame
+    let rekey = RekeyLimit {
+        bytes: u64::MAX,
+        frames: 1,
+    };
+
+    let builder = Server::builder(display_addr, "testkey")
+        .share(metadata, index)
+        .chunksize(16)
+        .rekey(rekey);
+    let (kill, server) = serve(&bind_addr, builder).await;
+
+    let client = Client::builder(display_addr, "testkey")
+        .output(outdir)
+        .rekey(rekey)
+        .build();
+    client.connection().await.unwrap();
+
+    kill.send(()).await.unwrap();
+    server.await.unwrap();
+
+    let recv_content = read_to_string("./tests/output/rekeyed.txt").await.unwrap();
+    assert_eq!(recv_content, testdata[0].1);
+
+    fs::remove_file("./tests/output/rekeyed.txt").unwrap();
+    fs::remove_file("./tests/data/rekeyed.txt").unwrap();
+}
+
+/// Binds the listener before handing it to the server, so clients can connect as soon as this returns.
+async fn serve(
+    bind_addr: &SocketAddr,
+    builder: ServerBuilder,
+) -> (mpsc::Sender<()>, JoinHandle<()>) {
+    let listener = TcpListener::bind(bind_addr).await.unwrap();
+    let (kill, rx) = mpsc::channel::<()>(1);
+
+    let server = tokio::spawn(async move {
+        builder.build().serve(rx, listener).await.unwrap();
+    });
+
+    (kill, server)
+}
+
+fn init_logger() {
+    // tests share the global logger, only the first initialization succeeds
+    let _ = env_logger::Builder::from_env(Env::default().default_filter_or("debug"))
+        .is_test(true)
+        .try_init();
+}
+
+/// Observer recording the names of the events, with consecutive chunks collapsed into one.
+fn recorder() -> (impl Observer, Arc<Mutex<Vec<&'static str>>>) {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let recorded = events.clone();
+
+    let observer = move |event: &Event<'_>| {
+        let mut events = recorded.lock().unwrap();
+
+        if events.last() != Some(&event.name()) || event.name() != "Chunk" {
+            events.push(event.name());
+        }
+    };
+
+    (observer, events)
+}
+
+fn testdata(names: &[&'static str]) -> (Vec<(&'static str, String)>, Vec<PathBuf>) {
+    let mut paths = Vec::new();
+    let testdata = names
+        .iter()
+        .map(|name| (*name, generate_data()))
+        .collect::<Vec<_>>();
 
     for file in &testdata {
         let filepath = PathBuf::from_str("./tests/data/").unwrap().join(file.0);
@@ -96,6 +845,5 @@ fn generate_data() -> String {
     thread_rng()
         .sample_iter(&Alphanumeric)
         .take(30)
-        .map(char::from)
         .collect::<String>()
 }